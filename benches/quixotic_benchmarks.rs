@@ -1,20 +1,38 @@
 use bytes::{BufMut, BytesMut};
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use quixotic::markov;
-use rand::Rng;
+use criterion::{criterion_group, criterion_main, Criterion};
+use quixotic::markov::{self, MarkovIterator};
+use rand::{rngs::StdRng, Rng, SeedableRng};
 
 pub fn ntokens_benchmark(c: &mut Criterion) {
-    let markov = markov::train("/home/marcusb/code/marcusb.org/public".into()).unwrap();
+    let markov = markov::train("/home/marcusb/code/marcusb.org/public".into(), 1)
+        .unwrap()
+        .into_default();
     c.bench_function("markov 128k n_tokens", |b| {
         b.iter(|| {
-            let tok = markov.n_tokens(128000);
+            let tok = markov.n_tokens(128000, &mut rand::rng());
             assert_eq!(tok.len(), 128000);
         })
     });
 }
 
+pub fn random_start_benchmark(c: &mut Criterion) {
+    // A synthetic 100k-word vocabulary, so this benchmark doesn't depend on a local corpus.  Each
+    // n_tokens call begins by picking a random start state, which dominates for short outputs.
+    let mut rng = StdRng::seed_from_u64(0);
+    let corpus = (0..1_000_000).map(|_| format!("w{}", rng.random_range(0..100_000)));
+    let markov = MarkovIterator::new(corpus).unwrap();
+    c.bench_function("markov random start 100k vocabulary", |b| {
+        b.iter(|| {
+            let tok = markov.n_tokens(1, &mut rng);
+            assert_eq!(tok.len(), 1);
+        })
+    });
+}
+
 pub fn linkmaze_benchmark(c: &mut Criterion) {
-    let markov = markov::train("/home/marcusb/code/marcusb.org/public".into()).unwrap();
+    let markov = markov::train("/home/marcusb/code/marcusb.org/public".into(), 1)
+        .unwrap()
+        .into_default();
     c.bench_function("linkmaze text generation n_tokens=128k", |b| {
         b.iter(|| {
             let uri = "/quixotic";
@@ -26,30 +44,39 @@ pub fn linkmaze_benchmark(c: &mut Criterion) {
             let mut res = BytesMut::with_capacity(n_tokens as usize * 12);
             res.put(&b"<!doctype html><html lang=en><head><title>"[..]);
             res.put(uri.as_bytes());
-            res.put(&b"</title></head><body><p>"[..]);
-
-            let tokens = markov.n_tokens(n_tokens);
-
-            for token in tokens {
-                let r = rng.random::<u8>();
-                res.put(&b" "[..]);
-                res.put(token.as_bytes());
-                if r < 5 {
-                    res.put(&b".</p><p>"[..]);
-                } else if r < 10 {
-                    let rand_link = quixotic::rand_link(&mut rng);
-                    res.put(&b" <a href=/"[..]);
-                    res.put(linkpath.as_bytes());
-                    res.put(&b"/"[..]);
-                    res.put(rand_link.as_bytes());
-                    res.put(&b".html>"[..]);
-                    res.put(rand_link.as_bytes());
-                    res.put(&b"</a>"[..]);
+            res.put(&b"</title></head><body>"[..]);
+
+            let mut written = 0;
+            while written < n_tokens {
+                res.put(&b"<p>"[..]);
+                for (i, word) in markov.paragraph(&mut rng).split(' ').enumerate() {
+                    if i > 0 {
+                        res.put(&b" "[..]);
+                    }
+                    res.put(word.as_bytes());
+                    written += 1;
+
+                    if rng.random::<u8>() < 5 {
+                        let rand_link = quixotic::rand_link(&mut rng);
+                        res.put(&b" <a href=/"[..]);
+                        res.put(linkpath.as_bytes());
+                        res.put(&b"/"[..]);
+                        res.put(rand_link.as_bytes());
+                        res.put(&b".html>"[..]);
+                        res.put(rand_link.as_bytes());
+                        res.put(&b"</a>"[..]);
+                    }
                 }
+                res.put(&b"</p>"[..]);
             }
         })
     });
 }
 
-criterion_group!(benches, ntokens_benchmark, linkmaze_benchmark);
+criterion_group!(
+    benches,
+    random_start_benchmark,
+    ntokens_benchmark,
+    linkmaze_benchmark
+);
 criterion_main!(benches);
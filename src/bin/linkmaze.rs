@@ -25,9 +25,18 @@ use actix_web::{
 use bytes::{BufMut, BytesMut};
 use clap::Parser;
 use rand::Rng;
-use std::process::exit;
+use std::{
+    fs::File,
+    hash::{DefaultHasher, Hash, Hasher},
+    io::BufReader,
+    process::exit,
+};
 
-use quixotic::markov::{train, MarkovIterator};
+use quixotic::{
+    markov::{MarkovIterator, Sampling, Trainer},
+    seeded_rng,
+    tokenize::TokenizerKind,
+};
 
 #[derive(Parser)]
 struct Args {
@@ -35,8 +44,12 @@ struct Args {
     linkpath: String,
     #[arg(short, long, default_value_t = 0.20)]
     percent: f32,
-    #[arg(short, long)]
-    train: String,
+    #[arg(short, long, required_unless_present = "model")]
+    train: Option<String>,
+    #[arg(short, long, conflicts_with_all = ["train", "order"])]
+    model: Option<String>,
+    #[arg(long, default_value_t = 1)]
+    order: usize,
     #[arg(long, default_value_t = 3005)]
     listen_port: u16,
     #[arg(long, default_value_t = String::from("0.0.0.0"))]
@@ -45,6 +58,17 @@ struct Args {
     min_tokens: u32,
     #[arg(long, default_value_t = 12500)]
     max_tokens: u32,
+    #[arg(long)]
+    seed: Option<u64>,
+    #[arg(long, default_value_t = 1.0)]
+    temperature: f32,
+    #[arg(long)]
+    top_k: Option<usize>,
+    #[arg(long, default_value_t = 0.0)]
+    restart_probability: f32,
+    /// How to split training text into words: "auto", "whitespace" or "unicode"
+    #[arg(long, default_value_t, conflicts_with = "model")]
+    tokenizer: TokenizerKind,
 }
 
 #[actix_web::main]
@@ -59,13 +83,33 @@ async fn main() -> Result<(), std::io::Error> {
         exit(1);
     }
 
-    let markov = train(args.train)?;
+    let mut markov = match (args.model, args.train) {
+        (Some(model), _) => MarkovIterator::load(BufReader::new(File::open(model)?))?,
+        (None, Some(input)) => {
+            let mut trainer = Trainer::new(args.order);
+            trainer.set_tokenizer(Box::new(args.tokenizer));
+            match trainer.add_dir(&input).and_then(|()| trainer.finish()) {
+                Ok(markov) => markov,
+                Err(e) => {
+                    eprintln!("Error: unable to train on {input}: {e}");
+                    exit(1);
+                }
+            }
+        }
+        (None, None) => unreachable!("clap requires --train or --model"),
+    };
+    markov.set_sampling(Sampling {
+        temperature: args.temperature,
+        top_k: args.top_k,
+        restart_probability: args.restart_probability,
+    });
 
     HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(args.linkpath.clone()))
             .app_data(web::Data::new(markov.clone()))
             .app_data(web::Data::new((args.min_tokens, args.max_tokens)))
+            .app_data(web::Data::new(args.seed))
             .service(maze)
     })
     .bind((args.listen_addr, args.listen_port))?
@@ -79,36 +123,47 @@ async fn maze(
     linkpath: web::Data<String>,
     markov: web::Data<MarkovIterator<String>>,
     limits: web::Data<(u32, u32)>,
+    seed: web::Data<Option<u64>>,
 ) -> impl Responder {
     let uri = path.into_inner();
     let (min_tokens, max_tokens) = *limits.into_inner();
 
-    let mut rng = rand::rng();
+    // With a fixed seed, each page is derived from the seed and its URI, so a given URL always
+    // serves the same content.
+    let mut rng = seeded_rng(seed.map(|seed| {
+        let mut hasher = DefaultHasher::new();
+        uri.hash(&mut hasher);
+        seed ^ hasher.finish()
+    }));
     let n_tokens = rng.random_range(min_tokens..max_tokens);
 
     let mut res = BytesMut::with_capacity(n_tokens as usize * 12);
     res.put(&b"<!doctype html><html lang=en><head><title>"[..]);
     res.put(uri.as_bytes());
-    res.put(&b"</title></head><body><p>"[..]);
+    res.put(&b"</title></head><body>"[..]);
 
-    let tokens = markov.n_tokens(n_tokens);
+    let mut written = 0;
+    while written < n_tokens {
+        res.put(&b"<p>"[..]);
+        for (i, word) in markov.paragraph(&mut rng).split(' ').enumerate() {
+            if i > 0 {
+                res.put(&b" "[..]);
+            }
+            res.put(word.as_bytes());
+            written += 1;
 
-    for token in tokens {
-        let r = rng.random::<u8>();
-        res.put(&b" "[..]);
-        res.put(token.as_bytes());
-        if r < 5 {
-            res.put(&b".</p><p>"[..]);
-        } else if r < 10 {
-            let rand_link = quixotic::rand_link(&mut rng);
-            res.put(&b" <a href=/"[..]);
-            res.put(linkpath.as_bytes());
-            res.put(&b"/"[..]);
-            res.put(rand_link.as_bytes());
-            res.put(&b".html>"[..]);
-            res.put(rand_link.as_bytes());
-            res.put(&b"</a>"[..]);
+            if rng.random::<u8>() < 5 {
+                let rand_link = quixotic::rand_link(&mut rng);
+                res.put(&b" <a href=/"[..]);
+                res.put(linkpath.as_bytes());
+                res.put(&b"/"[..]);
+                res.put(rand_link.as_bytes());
+                res.put(&b".html>"[..]);
+                res.put(rand_link.as_bytes());
+                res.put(&b"</a>"[..]);
+            }
         }
+        res.put(&b"</p>"[..]);
     }
 
     HttpResponse::Ok()
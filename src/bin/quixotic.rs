@@ -21,24 +21,69 @@
 // SOFTWARE.
 use std::{
     fs::{copy, create_dir, exists, read_to_string, File},
-    io::{BufWriter, Error, Write},
+    io::{BufReader, BufWriter, Error, Write},
     path::Path,
+    process::exit,
 };
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use html5ever::driver::ParseOpts;
 use html5ever::tendril::TendrilSink;
 use html5ever::tree_builder::TreeBuilderOpts;
 use html5ever::{parse_document, serialize};
-use rand::Rng;
+use rand::{rngs::StdRng, Rng};
 use walkdir::WalkDir;
 
 use quixotic::{
-    markov::{train, MarkovIterator},
-    rcdom::{RcDom, SerializableHandle},
+    canary::{canary, find_canaries, insert_sentence, page_name},
+    markov::{LanguageModels, Sampling, Trainer},
+    perturb::PerturbRates,
+    rcdom::{HandleOptions, LinkStyle, RcDom, SerializableHandle, SKIP_ELEMENTS},
+    replace::{scramble, Flow, Passage, ReplaceOptions},
+    rules::Rules,
+    seeded_rng,
+    tokenize::{Tokenizer, TokenizerKind},
 };
 
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    args: Option<Args>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Train a Markov chain and save it for use with --model
+    Train(TrainArgs),
+    /// Report which pages' canaries, added with --watermark-key, appear in a text.  Exits with
+    /// status 1 if none do.
+    Verify(VerifyArgs),
+}
+
+#[derive(clap::Args)]
+struct TrainArgs {
+    #[arg(short, long)]
+    input: String,
+    /// Where to save the model; defaults to the --update model
+    #[arg(short, long, required_unless_present = "update")]
+    model: Option<String>,
+    #[arg(long, default_value_t = 1)]
+    order: usize,
+    /// Add the input to this existing model instead of training from scratch
+    #[arg(long, conflicts_with = "order")]
+    update: Option<String>,
+    /// How to split text into words: "auto", "whitespace" or "unicode"
+    #[arg(long, default_value_t)]
+    tokenizer: TokenizerKind,
+    /// Comma-separated names of elements whose text is not used for training
+    #[arg(long, value_delimiter = ',', default_values = SKIP_ELEMENTS)]
+    skip_elements: Vec<String>,
+}
+
+#[derive(clap::Args)]
 struct Args {
     #[arg(long, default_value_t = false)]
     embed_linkmaze: bool,
@@ -46,23 +91,166 @@ struct Args {
     scramble_images: f32,
     #[arg(long)]
     linkmaze_path: Option<String>,
+    /// Scatter this many links into the linkmaze among each page's paragraphs, rather than adding
+    /// one at the start of the body
+    #[arg(long)]
+    linkmaze_links: Option<usize>,
+    /// How to hide links into the linkmaze: "visible", "hidden", "noscript" or "template"
+    #[arg(long, default_value_t)]
+    linkmaze_style: LinkStyle,
+    /// Fraction of relative links to other pages on the site to rewrite so they lead into the
+    /// linkmaze, keeping the original target in a data-quixotic-href attribute.  Absolute URLs are
+    /// left alone, even those pointing at the site itself
+    #[arg(long, default_value_t = 0.0, requires = "linkmaze_path")]
+    rewrite_links: f32,
     #[arg(short, long)]
     input: String,
+    #[arg(short, long, conflicts_with_all = ["train", "order"])]
+    model: Option<String>,
+    #[arg(long, default_value_t = 1)]
+    order: usize,
     #[arg(short, long)]
     output: String,
     #[arg(short, long, default_value_t = 0.20)]
     percent: f32,
     #[arg(short, long)]
     train: Option<String>,
+    #[arg(long)]
+    seed: Option<u64>,
+    #[arg(long, default_value_t = 1.0)]
+    temperature: f32,
+    #[arg(long)]
+    top_k: Option<usize>,
+    #[arg(long, default_value_t = 0.0)]
+    restart_probability: f32,
+    /// How to split text into words: "auto", "whitespace" or "unicode"
+    #[arg(long, default_value_t)]
+    tokenizer: TokenizerKind,
+    /// Comma-separated names of elements whose text is left untouched, and not used for training
+    #[arg(long, value_delimiter = ',', default_values = SKIP_ELEMENTS)]
+    skip_elements: Vec<String>,
+    /// File of CSS rules setting the replacement rate for matching elements
+    #[arg(long)]
+    rules: Option<String>,
+    /// Comma-separated names of attributes whose values are scrambled like text, e.g. "alt,title"
+    #[arg(long, value_delimiter = ',')]
+    scramble_attrs: Vec<String>,
+    /// Comma-separated names of meta elements whose content is scrambled like text, e.g.
+    /// "description,og:*,twitter:*".  Content that is a URL or a single word is left alone
+    #[arg(long, value_delimiter = ',')]
+    scramble_meta: Vec<String>,
+    /// Comma-separated keys whose string values are scrambled in JSON-LD scripts, or "" for none
+    #[arg(long, value_delimiter = ',', default_values = JSON_LD_KEYS)]
+    json_ld_keys: Vec<String>,
+    /// Keep the punctuation and capitalisation of replaced words, only swapping the word itself
+    #[arg(long, default_value_t = false)]
+    preserve_format: bool,
+    /// Choose replacement words close in length and capitalisation to the words they replace
+    #[arg(long, default_value_t = false)]
+    match_shape: bool,
+    /// Choose each replacement word to follow on from the word before it (and into the word after
+    /// it, where possible)
+    #[arg(long, default_value_t = false)]
+    contextual: bool,
+    /// Fraction of numbers to swap for random numbers of the same shape
+    #[arg(long, default_value_t = 0.0)]
+    perturb_numbers: f32,
+    /// Fraction of dates to swap for other dates in the same format
+    #[arg(long, default_value_t = 0.0)]
+    perturb_dates: f32,
+    /// Fraction of capitalised names to swap for made-up names of the same shape
+    #[arg(long, default_value_t = 0.0)]
+    perturb_names: f32,
+    /// Secret key from which to derive a canary phrase for each page, added to identify copies of
+    /// it later with the verify subcommand
+    #[arg(long)]
+    watermark_key: Option<String>,
+}
+
+/// Keys in JSON-LD structured data whose values are scrambled by default.
+const JSON_LD_KEYS: [&str; 3] = ["articleBody", "description", "headline"];
+
+#[derive(clap::Args)]
+struct VerifyArgs {
+    /// The site the canaries were added to
+    #[arg(short, long)]
+    input: String,
+    /// The secret key given to --watermark-key
+    #[arg(long)]
+    key: String,
+    /// File containing the suspicious text
+    text: String,
 }
 
 fn main() -> Result<(), Error> {
-    let args = Args::parse();
+    let cli = Cli::parse();
 
-    let mut res = train(args.train.unwrap_or(args.input.clone()))?;
+    match cli.command {
+        Some(Command::Train(args)) => train_model(args),
+        Some(Command::Verify(args)) => verify(args),
+        None => transform(cli.args.expect("clap requires the transform arguments")),
+    }
+}
+
+fn train_model(args: TrainArgs) -> Result<(), Error> {
+    let mut trainer = match args.update {
+        Some(ref model) => {
+            Trainer::update(&LanguageModels::load(BufReader::new(File::open(model)?))?)
+        }
+        None => Trainer::new(args.order),
+    };
+    trainer.set_tokenizer(Box::new(args.tokenizer));
+    trainer.set_skip_elements(args.skip_elements.into_iter().collect());
+    let res = train_or_exit(trainer, &args.input);
+
+    let model = args
+        .model
+        .or(args.update)
+        .expect("clap requires --model or --update");
+    res.save(BufWriter::new(File::create(model)?))
+}
+
+fn transform(args: Args) -> Result<(), Error> {
+    let rules = match args.rules {
+        Some(ref path) => match Rules::load(path) {
+            Ok(rules) => rules,
+            Err(e) => {
+                eprintln!("Error: unable to load rules from {path}: {e}");
+                exit(1);
+            }
+        },
+        None => Rules::default(),
+    };
+
+    let mut res = match args.model {
+        Some(ref model) => LanguageModels::load(BufReader::new(File::open(model)?))?,
+        None => {
+            let mut trainer = Trainer::new(args.order);
+            trainer.set_tokenizer(Box::new(args.tokenizer));
+            trainer.set_skip_elements(args.skip_elements.iter().cloned().collect());
+            train_or_exit(trainer, args.train.as_ref().unwrap_or(&args.input))
+        }
+    };
+    let mut rng = seeded_rng(args.seed);
+    res.set_rng(&mut rng);
+    res.set_sampling(Sampling {
+        temperature: args.temperature,
+        top_k: args.top_k,
+        restart_probability: args.restart_probability,
+    });
+    let replace = ReplaceOptions {
+        preserve_format: args.preserve_format,
+        match_shape: args.match_shape,
+        contextual: args.contextual,
+        perturb: PerturbRates {
+            numbers: args.perturb_numbers,
+            dates: args.perturb_dates,
+            names: args.perturb_names,
+        },
+    };
     let mut images = vec![];
 
-    for entry in WalkDir::new(&args.input) {
+    for entry in WalkDir::new(&args.input).sort_by_file_name() {
         let path = match entry {
             Ok(path) => path,
             Err(e) => panic!("unable to read input file: {e:?}"),
@@ -87,43 +275,64 @@ fn main() -> Result<(), Error> {
             _ => {}
         }
 
+        let canary = args
+            .watermark_key
+            .as_ref()
+            .map(|key| canary(key, &page_name(strip_input)));
+
         let output_buf = match path.path().extension().unwrap_or_default().to_str() {
             Some("html") => {
                 let contents = read_to_string(path.path())?;
                 transform_html(
                     contents,
                     &mut res,
-                    1.0 - args.percent,
-                    args.embed_linkmaze,
-                    args.linkmaze_path.clone(),
+                    &mut rng,
+                    &args.tokenizer,
+                    HandleOptions {
+                        percent: 1.0 - args.percent,
+                        embed_linkmaze: args.embed_linkmaze,
+                        linkmaze_path: args.linkmaze_path.clone(),
+                        linkmaze_links: args.linkmaze_links,
+                        linkmaze_style: args.linkmaze_style,
+                        rewrite_links: args.rewrite_links,
+                        canary: canary.clone(),
+                        skip_elements: args.skip_elements.iter().cloned().collect(),
+                        rules: rules.clone(),
+                        scramble_attrs: args.scramble_attrs.iter().cloned().collect(),
+                        scramble_meta: args.scramble_meta.iter().cloned().collect(),
+                        json_ld_keys: args
+                            .json_ld_keys
+                            .iter()
+                            .filter(|key| !key.is_empty())
+                            .cloned()
+                            .collect(),
+                        replace: replace.clone(),
+                    },
                 )
             }
             Some("txt") => {
-                let mut output_lines = vec![];
                 let contents = read_to_string(path.path())?;
-                for line in contents.lines() {
-                    let mut output_line = vec![];
-                    for word in line.split(" ") {
-                        if rand::random::<f32>() < (1.0 - args.percent) {
-                            output_line.push(String::from(word));
-                        } else {
-                            let Some(tok) = res.next() else {
-                                panic!("could not generate token!");
-                            };
-                            output_line.push(std::sync::Arc::unwrap_or_clone(tok));
-                        }
-                    }
-
-                    output_lines.push(output_line.join(" "));
+                let output = scramble(
+                    &contents,
+                    Passage {
+                        lang: None,
+                        keep: 1.0 - args.percent,
+                        flow: &mut Flow::default(),
+                    },
+                    &mut res,
+                    &mut rng,
+                    &args.tokenizer,
+                    &replace,
+                );
+                match canary {
+                    Some(ref canary) => insert_sentence(&output, canary, &mut rng),
+                    None => output,
                 }
-
-                output_lines.join("\n")
             }
             Some("png") | Some("gif") | Some("svg") | Some("jpg") | Some("jpeg") | Some("webp")
             | Some("avif")
                 if args.scramble_images > 0.00 =>
             {
-                let mut rng = rand::rng();
                 if rng.random::<f32>() > args.scramble_images {
                     copy(path.path(), &output_file)?;
                 } else {
@@ -147,12 +356,61 @@ fn main() -> Result<(), Error> {
     Ok(())
 }
 
+fn verify(args: VerifyArgs) -> Result<(), Error> {
+    let text = read_to_string(&args.text)?;
+
+    let mut pages = vec![];
+    for entry in WalkDir::new(&args.input).sort_by_file_name() {
+        let entry = entry?;
+        let Ok(relative) = entry.path().strip_prefix(&args.input) else {
+            panic!("file {:?} does not begin with {}", entry.path(), args.input);
+        };
+        match entry.path().extension().unwrap_or_default().to_str() {
+            Some("html") | Some("txt") if entry.file_type().is_file() => {
+                pages.push(page_name(relative))
+            }
+            _ => {}
+        }
+    }
+
+    let matches = find_canaries(&args.key, pages.iter().map(String::as_str), &text);
+    if matches.is_empty() {
+        println!("No canaries found.");
+        exit(1);
+    }
+    for found in matches {
+        if found.complete {
+            println!("{}: found canary {:?}", found.page, found.canary);
+        } else {
+            println!(
+                "{}: found {} of {} words of canary {:?}",
+                found.page, found.found, found.total, found.canary
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Train `trainer` on `input`, or exit with a useful message if that isn't possible.
+fn train_or_exit(mut trainer: Trainer, input: &str) -> LanguageModels {
+    match trainer
+        .add_dir(input)
+        .and_then(|()| trainer.finish_languages())
+    {
+        Ok(res) => res,
+        Err(e) => {
+            eprintln!("Error: unable to train on {input}: {e}");
+            exit(1);
+        }
+    }
+}
+
 fn transform_html(
     contents: String,
-    markov: &mut MarkovIterator<String>,
-    percent: f32,
-    embed_linkmaze: bool,
-    linkmaze_path: Option<String>,
+    markov: &mut LanguageModels,
+    rng: &mut StdRng,
+    tokenizer: &dyn Tokenizer,
+    options: HandleOptions,
 ) -> String {
     let opts = ParseOpts {
         tree_builder: TreeBuilderOpts {
@@ -165,8 +423,7 @@ fn transform_html(
     let dom = parse_document(RcDom::default(), opts).one(contents);
 
     let mut buf = BufWriter::new(Vec::new());
-    let document =
-        SerializableHandle::new(dom.document, markov, percent, embed_linkmaze, linkmaze_path);
+    let document = SerializableHandle::new(dom.document, markov, rng, tokenizer, options);
     serialize(&mut buf, &document, Default::default()).expect("serialization failure");
     let bytes = buf.into_inner().unwrap();
     String::from_utf8(bytes).unwrap()
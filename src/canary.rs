@@ -0,0 +1,180 @@
+// Copyright 2024 Marcus Butler <marcusb@marcusb.org>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Canary phrases: sentences of made-up words, derived from a secret key and a page's path, that
+//! are added to the page so that text copied from it, e.g. into a model's training data, can be
+//! traced back to it later.
+
+use std::{
+    cmp::Reverse,
+    path::{Component, Path},
+};
+
+use hmac_sha256::HMAC;
+use rand::Rng;
+
+const CONSONANTS: [char; 16] = [
+    'b', 'd', 'f', 'g', 'h', 'j', 'k', 'l', 'm', 'n', 'p', 'r', 's', 't', 'v', 'z',
+];
+const VOWELS: [char; 5] = ['a', 'e', 'i', 'o', 'u'];
+
+/// The number of words in a canary, and of syllables in each word.
+const WORDS: usize = 4;
+const SYLLABLES: usize = 3;
+
+/// The fewest words of a canary that must be found in a text for it to count as a match.
+const MIN_WORDS: usize = 2;
+
+/// The words of the canary for `page`, in lower case.
+fn canary_words(key: &str, page: &str) -> Vec<String> {
+    let mac = HMAC::mac(page.as_bytes(), key.as_bytes());
+    mac.chunks(SYLLABLES)
+        .take(WORDS)
+        .map(|syllables| {
+            syllables
+                .iter()
+                .flat_map(|&byte| {
+                    [
+                        CONSONANTS[usize::from(byte) % CONSONANTS.len()],
+                        VOWELS[usize::from(byte) / CONSONANTS.len() % VOWELS.len()],
+                    ]
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// The canary phrase for `page`, a sentence such as "Kovali temuro bisane pulodi.", derived from
+/// `key` so that only its holder can tell which page a canary belongs to.
+pub fn canary(key: &str, page: &str) -> String {
+    let mut phrase = canary_words(key, page).join(" ");
+    if let Some(first) = phrase.get(..1) {
+        phrase.replace_range(..1, &first.to_uppercase());
+    }
+    phrase.push('.');
+    phrase
+}
+
+/// The name of a page for [`canary`]: its path relative to the root of the site, with `/` between
+/// directories whatever the platform.
+pub fn page_name(relative: &Path) -> String {
+    relative
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Insert `sentence` into `text` after a randomly chosen sentence, or at the end if there are
+/// none.
+pub fn insert_sentence(text: &str, sentence: &str, mut rng: impl Rng) -> String {
+    let mut ends = text
+        .char_indices()
+        .zip(text.chars().skip(1))
+        .filter(|&((_, c), next)| matches!(c, '.' | '!' | '?') && next.is_whitespace())
+        .map(|((i, c), _)| i + c.len_utf8())
+        .collect::<Vec<_>>();
+    ends.push(text.trim_end().len());
+
+    let at = ends[rng.random_range(0..ends.len())];
+    format!("{} {sentence}{}", &text[..at], &text[at..])
+}
+
+/// A page whose canary was found, in whole or in part, in a text.
+#[derive(Debug, PartialEq)]
+pub struct CanaryMatch {
+    pub page: String,
+    pub canary: String,
+    /// The number of the canary's words found in the text.
+    pub found: usize,
+    pub total: usize,
+    /// Whether the whole canary was found, with its words in order.
+    pub complete: bool,
+}
+
+/// The pages whose canaries appear in `text`, with the closest matches first.  Only pages with
+/// at least two of their canary's words in `text` are included.
+pub fn find_canaries<'a>(
+    key: &str,
+    pages: impl IntoIterator<Item = &'a str>,
+    text: &str,
+) -> Vec<CanaryMatch> {
+    let text = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>();
+
+    let mut matches = pages
+        .into_iter()
+        .filter_map(|page| {
+            let words = canary_words(key, page);
+            let found = words.iter().filter(|word| text.contains(word)).count();
+            (found >= MIN_WORDS).then(|| CanaryMatch {
+                page: page.to_string(),
+                canary: canary(key, page),
+                found,
+                total: words.len(),
+                complete: text.windows(words.len()).any(|window| window == words),
+            })
+        })
+        .collect::<Vec<_>>();
+    matches.sort_by_key(|found| Reverse((found.complete, found.found)));
+    matches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seeded_rng;
+
+    #[test]
+    fn canaries() {
+        let phrase = canary("secret", "index.html");
+        assert_eq!(phrase, canary("secret", "index.html"));
+        assert_ne!(phrase, canary("secret", "about.html"));
+        assert_ne!(phrase, canary("other", "index.html"));
+        assert_eq!(phrase.split(' ').count(), WORDS);
+        assert!(phrase.starts_with(char::is_uppercase) && phrase.ends_with('.'));
+        assert_eq!(page_name(Path::new("./sub/notes.txt")), "sub/notes.txt");
+
+        let text = "It was a fine day. The sun shone!";
+        let watermarked = insert_sentence(text, &phrase, seeded_rng(Some(1)));
+        assert_eq!(watermarked.len(), text.len() + phrase.len() + 1);
+
+        let pages = ["index.html", "about.html", "sub/notes.txt"];
+        let copied = format!("Scraped: {}", watermarked.to_uppercase());
+        let matches = find_canaries("secret", pages, &copied);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].page, "index.html");
+        assert!(matches[0].complete);
+
+        let words = canary_words("secret", "sub/notes.txt");
+        let partial = format!("{} and {}", words[2], words[0]);
+        let matches = find_canaries("secret", pages, &partial);
+        assert_eq!(matches.len(), 1);
+        assert_eq!((matches[0].found, matches[0].complete), (2, false));
+
+        assert!(find_canaries("other", pages, &copied).is_empty());
+    }
+}
@@ -1,7 +1,12 @@
-use rand::{distr::Alphanumeric, Rng};
+use rand::{distr::Alphanumeric, rngs::StdRng, Rng, SeedableRng};
 
+pub mod canary;
 pub mod markov;
+pub mod perturb;
 pub mod rcdom;
+pub mod replace;
+pub mod rules;
+pub mod tokenize;
 
 pub fn rand_link(mut rng: impl Rng) -> String {
     let len = rng.random_range(4..16);
@@ -11,3 +16,12 @@ pub fn rand_link(mut rng: impl Rng) -> String {
         .map(char::from)
         .collect()
 }
+
+/// Create the RNG used for all random decisions: seeded from `seed` when one is given, so that
+/// output is reproducible, and from the thread-local generator otherwise.
+pub fn seeded_rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_rng(&mut rand::rng()),
+    }
+}
@@ -18,166 +18,1250 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
 // THE SOFTWARE.
 use std::{
-    cmp::PartialEq, collections::HashMap, fmt::Display, fs::read_to_string, hash::Hash, sync::Arc,
+    cmp::PartialEq,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fmt::{self, Display},
+    fs::read_to_string,
+    hash::Hash,
+    io::{BufRead, BufReader, Error, ErrorKind, Read, Write},
+    sync::{Arc, OnceLock},
 };
 
-use rand::Rng;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use walkdir::WalkDir;
 
-use crate::rcdom::tokenize_html;
+use crate::{
+    rcdom::{default_skip_elements, html_text},
+    tokenize::{is_unspaced, join_words, primary_language, Segment, Tokenizer, TokenizerKind},
+};
+
+/// An interned token: an index into the chain's symbol table.
+type Symbol = u32;
+
+/// The sentence boundary: states that begin with it are at the start of a sentence (the state made
+/// up entirely of boundaries is the start state), and a transition that emits it ends a sentence.
+const BOUNDARY: Symbol = Symbol::MAX;
+
+/// Marks a transition whose destination window never appeared as a state in training, i.e. a
+/// dead end that forces a restart.
+const NO_STATE: u32 = u32::MAX;
+
+/// Longest sentence [`MarkovIterator::sentences`] will generate before forcing it to end.
+const MAX_SENTENCE_TOKENS: usize = 40;
+
+/// A transition out of a state: the token it emits, the index of the state it leads to, and the
+/// running total of transition counts for this state up to and including this edge.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Edge {
+    token: Symbol,
+    next: u32,
+    cumulative: u32,
+}
+
+/// A Markov chain over interned tokens, which [`MarkovIterator`] walks.
+///
+/// Every distinct token is stored once in a symbol table and referred to by a `u32` symbol.  The
+/// states (windows of `order` symbols) are stored back to back in `states`, and their outgoing
+/// transitions in compressed sparse row form: the edges of state `i` are
+/// `edges[offsets[i]..offsets[i + 1]]`.  Each edge records the index of the state it leads to,
+/// so generation never needs to hash a state, and a cumulative count, so a weighted successor is
+/// found with a binary search.
+///
+/// A chain trained on sentences pads the states at the start of each sentence with [`BOUNDARY`],
+/// and ends every sentence with a transition that emits [`BOUNDARY`] and leads back to the start.
+#[derive(Clone)]
+struct Chain<T> {
+    order: usize,
+    symbols: Vec<Arc<T>>,
+    states: Vec<Symbol>,
+    offsets: Vec<u32>,
+    edges: Vec<Edge>,
+    /// The sentence start state, or [`NO_STATE`] if the chain wasn't trained on sentences.
+    start: u32,
+}
+
+/// Where a walk through the chain has got to.
+#[derive(Clone, Default)]
+struct Walk {
+    state: Option<u32>,
+    /// Tokens of a freshly chosen random state, emitted before anything is sampled from it.
+    pending: VecDeque<Symbol>,
+}
+
+/// Maps from tokens and states back to the symbols and state indices of a [`Chain`], for sampling
+/// in context.
+#[derive(Clone)]
+struct ChainIndex<T> {
+    symbols: HashMap<Arc<T>, Symbol>,
+    states: HashMap<Vec<Symbol>, u32>,
+}
+
+impl<T: Eq + Hash> ChainIndex<T> {
+    fn new(chain: &Chain<T>) -> Self {
+        ChainIndex {
+            symbols: chain
+                .symbols
+                .iter()
+                .enumerate()
+                .map(|(symbol, token)| (token.clone(), symbol as Symbol))
+                .collect(),
+            states: (0..chain.n_states())
+                .map(|idx| (chain.state(idx).to_vec(), idx))
+                .collect(),
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct MarkovIterator<T> {
-    tokens: Vec<Arc<T>>,
-    current_token: Option<Arc<T>>,
-    chain: HashMap<Arc<T>, Vec<Arc<T>>>,
+    chain: Chain<T>,
+    walk: Walk,
+    sampling: Sampling,
+    rng: StdRng,
+    /// Built the first time [`MarkovIterator::next_in_context`] needs it.
+    index: OnceLock<ChainIndex<T>>,
 }
 
-impl<T: Clone + Eq + Hash + PartialEq> MarkovIterator<T> {
-    pub fn new(tokens: impl Iterator<Item = T>) -> MarkovIterator<T> {
-        let mut markov = Self {
-            chain: HashMap::<Arc<T>, Vec<Arc<T>>>::new(),
-            current_token: None,
-            tokens: tokens.map(|x| Arc::new(x)).collect(),
+/// Errors that can occur while training a [`MarkovIterator`].
+#[derive(Debug)]
+pub enum TrainError {
+    /// The training corpus contained no tokens at all.
+    EmptyCorpus,
+    /// The training corpus was too short to contain a single transition for the chain's order.
+    CorpusTooSmall { tokens: usize },
+    /// A training file could not be read.
+    Io(Error),
+    /// Two chains of different orders were combined.
+    OrderMismatch { expected: usize, found: usize },
+}
+
+impl Display for TrainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrainError::EmptyCorpus => write!(f, "the training corpus contains no words"),
+            TrainError::CorpusTooSmall { tokens } => write!(
+                f,
+                "the training corpus is too small to build a chain (found {tokens} tokens)"
+            ),
+            TrainError::Io(e) => write!(f, "unable to read the training corpus: {e}"),
+            TrainError::OrderMismatch { expected, found } => write!(
+                f,
+                "cannot combine a chain of order {found} with one of order {expected}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TrainError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TrainError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for TrainError {
+    fn from(e: Error) -> Self {
+        TrainError::Io(e)
+    }
+}
+
+/// Controls how [`MarkovIterator`] picks each token, trading realism against entropy.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sampling {
+    /// Transition counts are raised to the power `1 / temperature` before sampling.  1.0 samples
+    /// in proportion to the training counts, values below 1.0 favour the most common transitions
+    /// (0.0 always picks the most common one) and values above 1.0 flatten the distribution.
+    pub temperature: f32,
+    /// Only consider the `top_k` most common transitions out of each state.
+    pub top_k: Option<usize>,
+    /// Probability of abandoning the chain after each token and restarting at a random state.
+    pub restart_probability: f32,
+}
+
+impl Default for Sampling {
+    fn default() -> Self {
+        Self {
+            temperature: 1.0,
+            top_k: None,
+            restart_probability: 0.0,
+        }
+    }
+}
+
+impl Sampling {
+    /// Pick an edge out of a state.
+    fn choose<'a>(&self, edges: &'a [Edge], rng: &mut impl Rng) -> &'a Edge {
+        if self.temperature == 1.0 && !matches!(self.top_k, Some(k) if k < edges.len()) {
+            let r = rng.random_range(0..edges[edges.len() - 1].cumulative);
+            return &edges[edges.partition_point(|edge| edge.cumulative <= r)];
+        }
+
+        let mut candidates = edges
+            .iter()
+            .scan(0, |previous, edge| {
+                let count = edge.cumulative - *previous;
+                *previous = edge.cumulative;
+                Some((count, edge))
+            })
+            .collect::<Vec<_>>();
+
+        if let Some(k) = self.top_k {
+            let k = k.clamp(1, candidates.len());
+            candidates.select_nth_unstable_by(k - 1, |a, b| b.0.cmp(&a.0));
+            candidates.truncate(k);
+        }
+
+        if self.temperature <= 0.0 {
+            let max = candidates
+                .iter()
+                .map(|(count, _)| *count)
+                .max()
+                .unwrap_or(0);
+            return candidates
+                .iter()
+                .find(|(count, _)| *count == max)
+                .map(|(_, edge)| *edge)
+                .unwrap_or(&edges[0]);
+        }
+
+        // Counts are scaled to at most 1 first, so that low temperatures can't overflow them.
+        let exponent = 1.0 / f64::from(self.temperature);
+        let max = candidates
+            .iter()
+            .map(|(count, _)| *count)
+            .max()
+            .unwrap_or(1);
+        let weights = candidates
+            .iter()
+            .map(|(count, _)| (f64::from(*count) / f64::from(max)).powf(exponent))
+            .collect::<Vec<_>>();
+
+        let mut r = rng.random::<f64>() * weights.iter().sum::<f64>();
+        for (weight, (_, edge)) in weights.iter().zip(&candidates) {
+            if r < *weight {
+                return edge;
+            }
+            r -= weight;
+        }
+        candidates[candidates.len() - 1].1
+    }
+}
+
+/// Collects transitions from a token stream before they are packed into a [`MarkovIterator`].
+struct ChainBuilder<T> {
+    order: usize,
+    symbols: Vec<Arc<T>>,
+    symbol_index: HashMap<Arc<T>, Symbol>,
+    states: Vec<Symbol>,
+    state_index: HashMap<Box<[Symbol]>, u32>,
+    /// Per state, each successor and how often it was seen, sorted by symbol.
+    transitions: Vec<Vec<(Symbol, u32)>>,
+    /// Number of tokens added, not counting sentence boundaries.
+    tokens: usize,
+}
+
+impl<T: Eq + Hash> ChainBuilder<T> {
+    fn new(order: usize) -> Self {
+        Self {
+            order: order.max(1),
+            symbols: vec![],
+            symbol_index: HashMap::new(),
+            states: vec![],
+            state_index: HashMap::new(),
+            transitions: vec![],
+            tokens: 0,
+        }
+    }
+
+    fn intern(&mut self, token: T) -> Symbol {
+        if let Some(symbol) = self.symbol_index.get(&token) {
+            return *symbol;
+        }
+
+        self.intern_shared(Arc::new(token))
+    }
+
+    /// Like [`ChainBuilder::intern`], but shares an existing allocation for a new token.
+    fn intern_shared(&mut self, token: Arc<T>) -> Symbol {
+        if let Some(symbol) = self.symbol_index.get(&token) {
+            return *symbol;
+        }
+
+        let symbol = Symbol::try_from(self.symbols.len()).expect("too many distinct tokens");
+        self.symbols.push(token.clone());
+        self.symbol_index.insert(token, symbol);
+        symbol
+    }
+
+    fn add_transition(&mut self, state: &[Symbol], next: Symbol, count: u32) {
+        let idx = match self.state_index.get(state) {
+            Some(idx) => *idx,
+            None => {
+                let idx = u32::try_from(self.transitions.len()).expect("too many states");
+                self.states.extend_from_slice(state);
+                self.state_index.insert(state.into(), idx);
+                self.transitions.push(vec![]);
+                idx
+            }
         };
 
-        let mut last = markov.tokens[0].clone();
-        for i in 0..markov.tokens.len() {
-            if i == 0 {
-                continue;
+        let links = &mut self.transitions[idx as usize];
+        match links.binary_search_by_key(&next, |&(token, _)| token) {
+            Ok(i) => links[i].1 = links[i].1.saturating_add(count),
+            Err(i) => links.insert(i, (next, count)),
+        }
+    }
+
+    /// Add every transition of an existing chain, summing counts with any already added.
+    fn add_chain(&mut self, chain: &Chain<T>) {
+        let symbols = chain
+            .symbols
+            .iter()
+            .map(|token| self.intern_shared(token.clone()))
+            .collect::<Vec<_>>();
+        let translate = |symbol: Symbol| match symbol {
+            BOUNDARY => BOUNDARY,
+            symbol => symbols[symbol as usize],
+        };
+
+        let mut state = Vec::with_capacity(self.order);
+        for idx in 0..chain.n_states() {
+            state.clear();
+            state.extend(chain.state(idx).iter().map(|&symbol| translate(symbol)));
+
+            let mut previous = 0;
+            for edge in chain.edges(idx) {
+                let count = edge.cumulative - previous;
+                previous = edge.cumulative;
+                if edge.token != BOUNDARY {
+                    self.tokens += count as usize;
+                }
+                self.add_transition(&state, translate(edge.token), count);
             }
+        }
+    }
 
-            if let Some(links) = markov.chain.get_mut(&last) {
-                links.push(markov.tokens[i].clone());
-            } else {
-                markov.chain.insert(last, vec![markov.tokens[i].clone()]);
+    /// Add one sentence, bracketed by the sentence start state and a final boundary transition.
+    fn add_sentence(&mut self, tokens: impl Iterator<Item = T>) {
+        let mut window = VecDeque::from(vec![BOUNDARY; self.order]);
+        for token in tokens {
+            self.tokens += 1;
+            let symbol = self.intern(token);
+            self.add_transition(window.make_contiguous(), symbol, 1);
+            window.pop_front();
+            window.push_back(symbol);
+        }
+
+        if window.iter().any(|&symbol| symbol != BOUNDARY) {
+            self.add_transition(window.make_contiguous(), BOUNDARY, 1);
+        }
+    }
+
+    /// Add every transition in a contiguous run of tokens, without any sentence boundaries.
+    fn add_tokens(&mut self, tokens: impl Iterator<Item = T>) {
+        let mut window = VecDeque::with_capacity(self.order + 1);
+        for token in tokens {
+            self.tokens += 1;
+            let symbol = self.intern(token);
+            if window.len() == self.order {
+                self.add_transition(window.make_contiguous(), symbol, 1);
+                window.pop_front();
             }
+            window.push_back(symbol);
+        }
+    }
 
-            last = markov.tokens[i].clone();
+    /// Build the chain, provided enough tokens were added to make one worth walking.
+    fn finish(self) -> Result<MarkovIterator<T>, TrainError> {
+        match self.tokens {
+            0 => Err(TrainError::EmptyCorpus),
+            tokens if tokens <= self.order || self.transitions.is_empty() => {
+                Err(TrainError::CorpusTooSmall { tokens })
+            }
+            _ => Ok(self.build()),
+        }
+    }
+
+    fn build(self) -> MarkovIterator<T> {
+        let start = self
+            .state_index
+            .get(&vec![BOUNDARY; self.order][..])
+            .copied()
+            .unwrap_or(NO_STATE);
+
+        let mut offsets = Vec::with_capacity(self.transitions.len() + 1);
+        let mut edges = Vec::with_capacity(self.transitions.iter().map(Vec::len).sum());
+        let mut key = Vec::with_capacity(self.order);
+
+        offsets.push(0);
+        for (i, links) in self.transitions.iter().enumerate() {
+            let state = &self.states[i * self.order..(i + 1) * self.order];
+            let mut cumulative = 0u32;
+            for &(token, count) in links {
+                key.clear();
+                key.extend_from_slice(&state[1..]);
+                key.push(token);
+                let next = if token == BOUNDARY {
+                    start
+                } else {
+                    self.state_index.get(&key[..]).copied().unwrap_or(NO_STATE)
+                };
+                cumulative = cumulative
+                    .checked_add(count)
+                    .expect("too many transitions from one state");
+                edges.push(Edge {
+                    token,
+                    next,
+                    cumulative,
+                });
+            }
+            offsets.push(u32::try_from(edges.len()).expect("too many transitions"));
         }
 
-        markov
+        MarkovIterator {
+            chain: Chain {
+                order: self.order,
+                symbols: self.symbols,
+                states: self.states,
+                offsets,
+                edges,
+                start,
+            },
+            walk: Walk::default(),
+            sampling: Sampling::default(),
+            rng: StdRng::from_rng(&mut rand::rng()),
+            index: OnceLock::new(),
+        }
     }
+}
 
-    fn random_token(&self) -> Arc<T> {
-        let tokens = self.chain.keys().count();
+impl<T: Clone + Eq + Hash + PartialEq> MarkovIterator<T> {
+    pub fn new(tokens: impl Iterator<Item = T>) -> Result<MarkovIterator<T>, TrainError> {
+        Self::with_order(tokens, 1)
+    }
+
+    /// Build a chain keyed on the previous `order` tokens.  An order of 1 gives a plain bigram
+    /// chain; higher orders produce more plausible text at the cost of a larger chain.
+    ///
+    /// Fails if `tokens` doesn't contain at least one transition, i.e. more than `order` tokens.
+    pub fn with_order(
+        tokens: impl Iterator<Item = T>,
+        order: usize,
+    ) -> Result<MarkovIterator<T>, TrainError> {
+        let mut builder = ChainBuilder::new(order);
+        builder.add_tokens(tokens);
+        builder.finish()
+    }
 
-        let mut rng = rand::rng();
-        let idx = rng.random_range(0..tokens);
+    /// Build a chain from a sequence of sentences.  The chain learns which tokens start and end
+    /// sentences, which [`MarkovIterator::sentences`] uses to generate whole sentences.
+    pub fn from_sentences<S: IntoIterator<Item = T>>(
+        sentences: impl IntoIterator<Item = S>,
+        order: usize,
+    ) -> Result<MarkovIterator<T>, TrainError> {
+        let mut builder = ChainBuilder::new(order);
+        for sentence in sentences {
+            builder.add_sentence(sentence.into_iter());
+        }
+        builder.finish()
+    }
+
+    pub fn order(&self) -> usize {
+        self.chain.order
+    }
+
+    /// Merge the transitions of `other` into this chain, summing the counts of transitions both
+    /// chains contain.  Both chains must have the same order.
+    pub fn merge(&mut self, other: &MarkovIterator<T>) -> Result<(), TrainError> {
+        if other.order() != self.order() {
+            return Err(TrainError::OrderMismatch {
+                expected: self.order(),
+                found: other.order(),
+            });
+        }
+
+        let mut builder = ChainBuilder::new(self.order());
+        builder.add_chain(&self.chain);
+        builder.add_chain(&other.chain);
+        self.chain = builder.build().chain;
+        self.walk = Walk::default();
+        self.index = OnceLock::new();
+        Ok(())
+    }
+
+    /// Replace the RNG used by the [`Iterator`] implementation, e.g. with a seeded one for
+    /// reproducible output.
+    pub fn set_rng(&mut self, rng: StdRng) {
+        self.rng = rng;
+        self.walk = Walk::default();
+    }
+
+    pub fn sampling(&self) -> Sampling {
+        self.sampling
+    }
 
+    pub fn set_sampling(&mut self, sampling: Sampling) {
+        self.sampling = sampling;
+    }
+
+    /// Sample the token to follow `previous`, the tokens before it in the current sentence with
+    /// the most recent last, rather than the token that continues the walk so far.  Tokens that
+    /// can be followed by `following` are preferred, and tokens for which `avoid` is true are only
+    /// sampled if there are no others.  Carries on the walk instead if the chain has never seen
+    /// the last `order` tokens of `previous`.
+    pub fn next_in_context(
+        &mut self,
+        previous: &[T],
+        following: Option<&T>,
+        avoid: impl Fn(&T) -> bool,
+    ) -> Arc<T> {
+        let index = self.index.get_or_init(|| ChainIndex::new(&self.chain));
+        let symbol = self
+            .chain
+            .sample_after(
+                index,
+                previous,
+                following,
+                avoid,
+                &self.sampling,
+                &mut self.rng,
+            )
+            .unwrap_or_else(|| {
+                self.chain
+                    .step(&mut self.walk, &self.sampling, &mut self.rng)
+            });
+        self.chain.token(symbol)
+    }
+
+    pub fn n_tokens(&self, n: u32, rng: &mut impl Rng) -> Vec<Arc<T>> {
+        let mut walk = Walk::default();
+        (0..n)
+            .map(|_| {
+                self.chain
+                    .token(self.chain.step(&mut walk, &self.sampling, rng))
+            })
+            .collect()
+    }
+}
+
+impl<T> Chain<T> {
+    fn n_states(&self) -> u32 {
+        (self.offsets.len() - 1) as u32
+    }
+
+    fn random_state(&self, rng: &mut impl Rng) -> u32 {
+        rng.random_range(0..self.n_states())
+    }
+
+    /// The symbols making up state `idx`.
+    fn state(&self, idx: u32) -> &[Symbol] {
+        let start = idx as usize * self.order;
+        &self.states[start..start + self.order]
+    }
+
+    fn edges(&self, idx: u32) -> &[Edge] {
+        &self.edges[self.offsets[idx as usize] as usize..self.offsets[idx as usize + 1] as usize]
+    }
+
+    fn token(&self, symbol: Symbol) -> Arc<T> {
+        self.symbols[symbol as usize].clone()
+    }
+
+    /// Jump to a random state, queueing its tokens so they are emitted before anything is
+    /// sampled from it.
+    fn restart(&self, walk: &mut Walk, rng: &mut impl Rng) {
+        let state = self.random_state(rng);
+        walk.pending.clear();
+        walk.pending.extend(
+            self.state(state)
+                .iter()
+                .filter(|&&symbol| symbol != BOUNDARY),
+        );
+        walk.state = Some(state);
+    }
+
+    /// Produce the next token of a free-running walk.  At a sentence boundary the walk carries on
+    /// into the next sentence; at a dead end it restarts from a random state.
+    fn step(&self, walk: &mut Walk, sampling: &Sampling, rng: &mut impl Rng) -> Symbol {
         loop {
-            let Some(tok) = self.chain.keys().nth(idx).cloned() else {
+            if let Some(symbol) = walk.pending.pop_front() {
+                return symbol;
+            }
+
+            let Some(state) = walk.state else {
+                self.restart(walk, rng);
                 continue;
             };
-            return tok;
+
+            let edge = sampling.choose(self.edges(state), rng);
+            walk.state = (edge.next != NO_STATE).then_some(edge.next);
+            if sampling.restart_probability > 0.0
+                && rng.random::<f32>() < sampling.restart_probability
+            {
+                walk.state = None;
+            }
+
+            if edge.token != BOUNDARY {
+                return edge.token;
+            }
         }
     }
 
-    pub fn n_tokens(&self, n: u32) -> Vec<Arc<T>> {
-        let mut tokens = vec![];
-        let mut rng = rand::rng();
-        let mut current_token = self.random_token();
-        for _ in 0..n {
-            let Some(links) = self.chain.get(&current_token) else {
-                current_token = self.random_token();
-                continue;
-            };
+    /// Generate the symbols of one sentence.  Chains trained on sentences run from the start
+    /// state to a boundary; others start at a random state and stop after a token for which
+    /// `ends_sentence` is true.  Either way, a dead end or [`MAX_SENTENCE_TOKENS`] also ends it.
+    fn sentence(
+        &self,
+        sampling: &Sampling,
+        rng: &mut impl Rng,
+        ends_sentence: impl Fn(&T) -> bool,
+    ) -> Vec<Symbol> {
+        let mut walk = Walk::default();
+        if self.start == NO_STATE {
+            self.restart(&mut walk, rng);
+        } else {
+            walk.state = Some(self.start);
+        }
 
-            if links.is_empty() {
-                current_token = self.random_token();
-                continue;
+        let mut symbols = Vec::from(walk.pending);
+        let mut state = walk.state;
+        while let Some(current) = state {
+            if symbols.len() >= MAX_SENTENCE_TOKENS
+                || symbols
+                    .last()
+                    .is_some_and(|&symbol| ends_sentence(&self.symbols[symbol as usize]))
+            {
+                break;
             }
 
-            let next_token = links[rng.random_range(0..links.len())].clone();
+            let edge = sampling.choose(self.edges(current), rng);
+            if edge.token == BOUNDARY {
+                break;
+            }
+            symbols.push(edge.token);
+            state = (edge.next != NO_STATE).then_some(edge.next);
+        }
+
+        symbols
+    }
+}
 
-            tokens.push(current_token);
-            current_token = next_token;
+impl<T: Eq + Hash> Chain<T> {
+    /// Sample a token, other than the boundary, to follow `previous`, preferring tokens that lead
+    /// to a state with a transition to `following`.  Tokens for which `avoid` is true are only
+    /// sampled if there are no others.  `None` if there is no such state, or it has no tokens to
+    /// sample.
+    fn sample_after(
+        &self,
+        index: &ChainIndex<T>,
+        previous: &[T],
+        following: Option<&T>,
+        avoid: impl Fn(&T) -> bool,
+        sampling: &Sampling,
+        rng: &mut impl Rng,
+    ) -> Option<Symbol> {
+        // States at the start of a sentence are padded with boundaries, as in training.
+        let previous = &previous[previous.len().saturating_sub(self.order)..];
+        let mut key = vec![BOUNDARY; self.order - previous.len()];
+        for token in previous {
+            key.push(*index.symbols.get(token)?);
         }
+        let edges = self.edges(*index.states.get(&key)?);
 
-        tokens
+        let following = following.and_then(|token| index.symbols.get(token).copied());
+        let leads_to_following = |edge: &Edge| {
+            following.is_some_and(|following| {
+                edge.next != NO_STATE
+                    && self
+                        .edges(edge.next)
+                        .iter()
+                        .any(|next| next.token == following)
+            })
+        };
+
+        let allowed =
+            |edge: &Edge| edge.token != BOUNDARY && !avoid(&self.symbols[edge.token as usize]);
+
+        let mut candidates = reweigh(edges, |edge| allowed(edge) && leads_to_following(edge));
+        if candidates.is_empty() {
+            candidates = reweigh(edges, allowed);
+        }
+        if candidates.is_empty() {
+            candidates = reweigh(edges, |edge| edge.token != BOUNDARY);
+        }
+        (!candidates.is_empty()).then(|| sampling.choose(&candidates, rng).token)
     }
 }
 
+/// The edges passing `filter`, with their cumulative counts recomputed over just those edges.
+fn reweigh(edges: &[Edge], filter: impl Fn(&Edge) -> bool) -> Vec<Edge> {
+    let mut previous = 0;
+    let mut total = 0;
+    edges
+        .iter()
+        .filter_map(|edge| {
+            let count = edge.cumulative - previous;
+            previous = edge.cumulative;
+            filter(edge).then(|| {
+                total += count;
+                Edge {
+                    cumulative: total,
+                    ..*edge
+                }
+            })
+        })
+        .collect()
+}
+
 impl<T: Clone + std::fmt::Debug + Display + Eq + Hash> Iterator for MarkovIterator<T> {
     type Item = Arc<T>;
 
     fn next(&mut self) -> Option<Arc<T>> {
-        let mut rng = rand::rng();
+        let symbol = self
+            .chain
+            .step(&mut self.walk, &self.sampling, &mut self.rng);
+        Some(self.chain.token(symbol))
+    }
+}
 
-        loop {
-            if self.current_token.is_none() {
-                self.current_token = Some(self.random_token());
+/// Magic bytes at the start of every saved model.
+const MODEL_MAGIC: &[u8; 4] = b"QXM\0";
+
+/// Version of the on-disk model format.  Bump this whenever the layout written by
+/// [`MarkovIterator::save`] or [`LanguageModels::save`] changes.
+pub const MODEL_VERSION: u32 = 4;
+
+impl MarkovIterator<String> {
+    /// Write the trained chain to `writer` in the versioned quixotic model format.
+    ///
+    /// The layout is a header (magic and format version) followed by the chain: its order, a
+    /// symbol table of every distinct token, and the states and transitions, with each state and
+    /// transition stored as indices into the symbol table and each transition followed by its
+    /// count.  Sentence boundaries are stored as `u32::MAX`.  Per-language chains follow, as
+    /// written by [`LanguageModels::save`]; there are none here.  All integers are little-endian
+    /// `u32`s.
+    pub fn save(&self, mut writer: impl Write) -> Result<(), Error> {
+        write_header(&mut writer)?;
+        self.write_chain(&mut writer)?;
+        write_u32(&mut writer, 0)?;
+        writer.flush()
+    }
+
+    /// Read a chain previously written by [`MarkovIterator::save`].  Fails with
+    /// [`ErrorKind::InvalidData`] if the input is not a quixotic model or was written with a
+    /// different format version.  Only the default chain of a model written by
+    /// [`LanguageModels::save`] is read.
+    pub fn load(mut reader: impl Read) -> Result<MarkovIterator<String>, Error> {
+        read_header(&mut reader)?;
+        Self::read_chain(&mut reader)
+    }
+
+    fn write_chain(&self, writer: &mut impl Write) -> Result<(), Error> {
+        write_u32(writer, to_u32(self.chain.order)?)?;
+
+        write_u32(writer, to_u32(self.chain.symbols.len())?)?;
+        for symbol in &self.chain.symbols {
+            write_str(writer, symbol)?;
+        }
+
+        write_u32(writer, self.chain.n_states())?;
+        for idx in 0..self.chain.n_states() {
+            for &symbol in self.chain.state(idx) {
+                write_u32(writer, symbol)?;
             }
 
-            let Some(token) = self.current_token.clone() else {
-                self.current_token = None;
-                continue;
-            };
+            let edges = self.chain.edges(idx);
+            write_u32(writer, to_u32(edges.len())?)?;
+            let mut previous = 0;
+            for edge in edges {
+                write_u32(writer, edge.token)?;
+                write_u32(writer, edge.cumulative - previous)?;
+                previous = edge.cumulative;
+            }
+        }
 
-            let Some(links) = self.chain.get(&token) else {
-                self.current_token = None;
-                continue;
+        Ok(())
+    }
+
+    fn read_chain(reader: &mut impl Read) -> Result<MarkovIterator<String>, Error> {
+        let order = read_u32(reader)? as usize;
+        if order == 0 {
+            return Err(invalid_model("model has a chain order of 0"));
+        }
+
+        let mut builder = ChainBuilder::new(order);
+        let n_symbols = read_u32(reader)?;
+        for _ in 0..n_symbols {
+            let symbol = read_str(reader)?;
+            if builder.symbol_index.contains_key(&symbol) {
+                return Err(invalid_model("duplicate symbol"));
+            }
+            builder.intern(symbol);
+        }
+        if builder.symbols.len() != n_symbols as usize {
+            return Err(invalid_model("symbol table has the wrong length"));
+        }
+        let read_symbol = |reader: &mut _| -> Result<Symbol, Error> {
+            let symbol = read_u32(reader)?;
+            if symbol >= n_symbols && symbol != BOUNDARY {
+                return Err(invalid_model("symbol index out of range"));
+            }
+            Ok(symbol)
+        };
+
+        let n_states = read_u32(reader)?;
+        if n_states == 0 {
+            return Err(invalid_model("model has no states"));
+        }
+
+        let mut state = Vec::with_capacity(order);
+        for _ in 0..n_states {
+            state.clear();
+            for _ in 0..order {
+                state.push(read_symbol(reader)?);
+            }
+
+            let n_links = read_u32(reader)?;
+            if n_links == 0 {
+                return Err(invalid_model("state has no transitions"));
+            }
+            for _ in 0..n_links {
+                let next = read_symbol(reader)?;
+                let count = read_u32(reader)?;
+                if count == 0 {
+                    return Err(invalid_model("transition has a count of 0"));
+                }
+                builder.add_transition(&state, next, count);
+            }
+        }
+
+        Ok(builder.build())
+    }
+
+    /// Generate `n` sentences, each capitalised and ending in punctuation.
+    pub fn sentences(&self, n: usize, rng: &mut impl Rng) -> Vec<String> {
+        let mut sentences = Vec::with_capacity(n);
+        // A chain of nothing but empty tokens never produces a sentence, so give up eventually.
+        for _ in 0..n.saturating_mul(10) {
+            if sentences.len() == n {
+                break;
+            }
+
+            let symbols = self
+                .chain
+                .sentence(&self.sampling, rng, |token| ends_sentence(token));
+            let words = symbols
+                .iter()
+                .map(|&symbol| self.chain.symbols[symbol as usize].as_str())
+                .filter(|word| !word.is_empty())
+                .collect::<Vec<_>>();
+
+            if !words.is_empty() {
+                sentences.push(punctuate(&join_words(&words)));
+            }
+        }
+        sentences
+    }
+
+    /// Generate a paragraph of between three and seven sentences.
+    pub fn paragraph(&self, rng: &mut impl Rng) -> String {
+        let n = rng.random_range(3..=7);
+        self.sentences(n, rng).join(" ")
+    }
+}
+
+/// Whether `token` ends a sentence, i.e. ends in terminal punctuation, possibly followed by a
+/// closing quote or bracket.
+pub fn ends_sentence(token: &str) -> bool {
+    token
+        .trim_end_matches(['"', '\'', '”', '’', ')', ']'])
+        .ends_with(['.', '!', '?', '。', '！', '？'])
+}
+
+/// Capitalise the start of `sentence` and make sure it ends in terminal punctuation.
+fn punctuate(sentence: &str) -> String {
+    let mut chars = sentence.chars();
+    let mut out = match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect::<String>(),
+        None => String::new(),
+    };
+
+    if !ends_sentence(&out) {
+        out.truncate(out.trim_end_matches([',', ';', ':', '-', '—', '、']).len());
+        match out.chars().next_back() {
+            Some(last) if is_unspaced(last) => out.push('。'),
+            _ => out.push('.'),
+        }
+    }
+    out
+}
+
+fn write_header(writer: &mut impl Write) -> Result<(), Error> {
+    writer.write_all(MODEL_MAGIC)?;
+    write_u32(writer, MODEL_VERSION)
+}
+
+fn read_header(reader: &mut impl Read) -> Result<(), Error> {
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MODEL_MAGIC {
+        return Err(invalid_model("not a quixotic model file"));
+    }
+
+    let version = read_u32(reader)?;
+    if version != MODEL_VERSION {
+        return Err(invalid_model(&format!(
+            "unsupported model format version {version} (this build reads version {MODEL_VERSION}); retrain the model with `quixotic train`"
+        )));
+    }
+    Ok(())
+}
+
+fn invalid_model(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("invalid model: {msg}"))
+}
+
+fn to_u32(n: usize) -> Result<u32, Error> {
+    u32::try_from(n).map_err(|_| invalid_model("too large to save"))
+}
+
+fn write_u32(writer: &mut impl Write, n: u32) -> Result<(), Error> {
+    writer.write_all(&n.to_le_bytes())
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32, Error> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn write_str(writer: &mut impl Write, s: &str) -> Result<(), Error> {
+    write_u32(writer, to_u32(s.len())?)?;
+    writer.write_all(s.as_bytes())
+}
+
+fn read_str(reader: &mut impl Read) -> Result<String, Error> {
+    let len = read_u32(reader)? as usize;
+    let mut buf = vec![0u8; len];
+    reader.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|_| invalid_model("string is not valid UTF-8"))
+}
+
+/// A chain being trained, and the sentence currently being added to it.
+struct SentenceBuilder {
+    builder: ChainBuilder<String>,
+    sentence: Vec<String>,
+}
+
+impl SentenceBuilder {
+    fn new(order: usize) -> Self {
+        Self {
+            builder: ChainBuilder::new(order),
+            sentence: vec![],
+        }
+    }
+
+    /// Finish the sentence in progress, if any.
+    fn end_sentence(&mut self) {
+        if !self.sentence.is_empty() {
+            self.builder.add_sentence(self.sentence.drain(..));
+        }
+    }
+
+    fn finish(mut self) -> Result<MarkovIterator<String>, TrainError> {
+        self.end_sentence();
+        self.builder.finish()
+    }
+}
+
+/// Trains a [`MarkovIterator<String>`] from documents supplied one at a time, so a corpus can be
+/// streamed from anywhere rather than read from a directory up front.
+///
+/// Each document is split into sentences at tokens ending in terminal punctuation, so the chain
+/// learns how sentences start and end.  A sentence never spans two documents.
+///
+/// Text in HTML documents is also added to a chain for its language, taken from the nearest
+/// `lang` attribute, which [`Trainer::finish_languages`] returns alongside the chain of
+/// everything.
+///
+/// Documents are split into words with [`TokenizerKind::Auto`] unless another [`Tokenizer`] is set
+/// with [`Trainer::set_tokenizer`].  The text of the elements in
+/// [`SKIP_ELEMENTS`](crate::rcdom::SKIP_ELEMENTS) isn't used,
+/// unless another set is given with [`Trainer::set_skip_elements`].
+pub struct Trainer {
+    order: usize,
+    default: SentenceBuilder,
+    languages: BTreeMap<String, SentenceBuilder>,
+    tokenizer: Box<dyn Tokenizer>,
+    skip_elements: HashSet<String>,
+}
+
+impl Trainer {
+    pub fn new(order: usize) -> Self {
+        Self {
+            order,
+            default: SentenceBuilder::new(order),
+            languages: BTreeMap::new(),
+            tokenizer: Box::new(TokenizerKind::Auto),
+            skip_elements: default_skip_elements(),
+        }
+    }
+
+    pub fn set_tokenizer(&mut self, tokenizer: Box<dyn Tokenizer>) {
+        self.tokenizer = tokenizer;
+    }
+
+    /// Set the names of HTML elements whose text isn't used for training.
+    pub fn set_skip_elements(&mut self, skip_elements: HashSet<String>) {
+        self.skip_elements = skip_elements;
+    }
+
+    /// Continue training existing models: documents added are counted on top of the transitions
+    /// they already have.
+    pub fn update(models: &LanguageModels) -> Self {
+        let mut trainer = Self::new(models.default.order());
+        trainer.default.builder.add_chain(&models.default.chain);
+        for (lang, markov) in &models.languages {
+            let mut language = SentenceBuilder::new(trainer.order);
+            language.builder.add_chain(&markov.chain);
+            trainer.languages.insert(lang.clone(), language);
+        }
+        trainer
+    }
+
+    /// Add the `.html` and `.txt` files under `input`, in file name order.  Files that aren't
+    /// valid UTF-8 are skipped.
+    pub fn add_dir(&mut self, input: &str) -> Result<(), TrainError> {
+        for entry in WalkDir::new(input).sort_by_file_name() {
+            let path = match entry {
+                Ok(path) => path,
+                Err(e) => return Err(TrainError::Io(e.into())),
             };
 
-            if links.is_empty() {
-                self.current_token = None;
+            if !path.file_type().is_file() {
                 continue;
             }
 
-            let next_token = links[rng.random_range(0..links.len())].clone();
+            match path.path().extension().unwrap_or_default().to_str() {
+                Some("html") => {
+                    let Ok(contents) = read_to_string(path.path()) else {
+                        continue;
+                    };
 
-            self.current_token = Some(next_token);
-            return Some(token);
+                    self.add_html(&contents);
+                }
+                Some("txt") => {
+                    let Ok(contents) = read_to_string(path.path()) else {
+                        continue;
+                    };
+
+                    self.add_text(&contents);
+                }
+                _ => continue,
+            }
         }
+
+        Ok(())
     }
-}
 
-pub fn train(input: String) -> Result<MarkovIterator<String>, std::io::Error> {
-    let mut tokens = vec![];
-    for entry in WalkDir::new(input) {
-        let path = match entry {
-            Ok(path) => path,
-            Err(e) => panic!("unable to read training file: {e:?}"),
-        };
+    /// Add a plain text document.
+    pub fn add_text(&mut self, text: &str) {
+        self.add_segments(text, None);
+        self.end_sentence();
+    }
 
-        if !path.file_type().is_file() {
-            continue;
+    /// Add an HTML document.  Only the text content is used, and block elements such as headings,
+    /// list items and table cells end the sentence in progress.
+    pub fn add_html(&mut self, html: &str) {
+        for node in html_text(html, &self.skip_elements) {
+            if node.block_start {
+                self.end_sentence();
+            }
+            self.add_segments(&node.text, node.lang.as_deref());
         }
+        self.end_sentence();
+    }
 
-        match path.path().extension().unwrap_or_default().to_str() {
-            Some("html") => {
-                let Ok(contents) = read_to_string(path.path()) else {
-                    continue;
-                };
+    /// Add a plain text document, reading it a line at a time.
+    pub fn add_reader(&mut self, reader: impl Read) -> Result<(), TrainError> {
+        let result = BufReader::new(reader).lines().try_for_each(|line| {
+            self.add_segments(&line?, None);
+            Ok::<_, Error>(())
+        });
+        self.end_sentence();
+        Ok(result?)
+    }
 
-                tokens.extend(tokenize_html(contents));
+    /// Build the chain of every document added.  Fails if the documents added don't contain enough
+    /// tokens.
+    pub fn finish(self) -> Result<MarkovIterator<String>, TrainError> {
+        self.default.finish()
+    }
+
+    /// Build the chain of every document added, and a chain for each language seen.  Fails if
+    /// the documents added don't contain enough tokens; languages without enough tokens to build
+    /// a chain are left out.
+    pub fn finish_languages(self) -> Result<LanguageModels, TrainError> {
+        let mut models = LanguageModels::from(self.default.finish()?);
+        for (lang, language) in self.languages {
+            if let Ok(markov) = language.finish() {
+                models.languages.insert(lang, markov);
             }
-            Some("txt") => {
-                let Ok(contents) = read_to_string(path.path()) else {
-                    continue;
-                };
+        }
+        Ok(models)
+    }
+
+    /// Add the words of `text`, ending the sentence in progress after a word or punctuation that
+    /// ends a sentence.
+    fn add_segments(&mut self, text: &str, lang: Option<&str>) {
+        let order = self.order;
+        let mut language = lang
+            .map(primary_language)
+            .filter(|lang| !lang.is_empty())
+            .map(|lang| {
+                self.languages
+                    .entry(lang)
+                    .or_insert_with(|| SentenceBuilder::new(order))
+            });
 
-                for line in contents.lines() {
-                    let words = line.split(' ');
-
-                    for word in words {
-                        let word = word
-                            .chars()
-                            .filter(|x| match x {
-                                ',' | '.' | '!' | '?' | ':' | ';' => true,
-                                '\n' | '\r' | '"' | '\'' => false,
-                                _ => true,
-                            })
-                            .collect::<String>();
-                        tokens.push(word);
-                    }
+        for segment in self.tokenizer.segments_in(text, lang) {
+            if let Segment::Word(word) = segment {
+                self.default.sentence.push(word.into());
+                if let Some(language) = &mut language {
+                    language.sentence.push(word.into());
+                }
+            }
+            if ends_sentence(segment.as_str().trim_end()) {
+                self.default.end_sentence();
+                if let Some(language) = &mut language {
+                    language.end_sentence();
                 }
             }
-            _ => continue,
         }
     }
 
-    Ok(MarkovIterator::new(tokens.into_iter()))
+    /// Finish the sentence in progress in every chain, at the end of a document.
+    fn end_sentence(&mut self) {
+        self.default.end_sentence();
+        for language in self.languages.values_mut() {
+            language.end_sentence();
+        }
+    }
+}
+
+/// A chain trained on everything plus a chain per language, so that text can be replaced with
+/// words in the same language.
+#[derive(Clone)]
+pub struct LanguageModels {
+    default: MarkovIterator<String>,
+    /// Keyed by primary language subtag, e.g. `de`.
+    languages: BTreeMap<String, MarkovIterator<String>>,
+}
+
+impl From<MarkovIterator<String>> for LanguageModels {
+    fn from(default: MarkovIterator<String>) -> Self {
+        Self {
+            default,
+            languages: BTreeMap::new(),
+        }
+    }
+}
+
+impl LanguageModels {
+    /// The chain trained on every language.
+    pub fn default_model(&self) -> &MarkovIterator<String> {
+        &self.default
+    }
+
+    pub fn into_default(self) -> MarkovIterator<String> {
+        self.default
+    }
+
+    /// Use `markov` for text in `lang`.
+    pub fn insert(&mut self, lang: &str, markov: MarkovIterator<String>) {
+        self.languages.insert(primary_language(lang), markov);
+    }
+
+    /// The languages with a chain of their own, in sorted order.
+    pub fn languages(&self) -> impl Iterator<Item = &str> {
+        self.languages.keys().map(String::as_str)
+    }
+
+    /// The chain for text in `lang`, falling back to the default chain for text in an unknown
+    /// language.
+    pub fn get(&self, lang: Option<&str>) -> &MarkovIterator<String> {
+        lang.and_then(|lang| self.languages.get(&primary_language(lang)))
+            .unwrap_or(&self.default)
+    }
+
+    pub fn get_mut(&mut self, lang: Option<&str>) -> &mut MarkovIterator<String> {
+        match lang.map(primary_language) {
+            Some(lang) if self.languages.contains_key(&lang) => {
+                self.languages.get_mut(&lang).expect("language is present")
+            }
+            _ => &mut self.default,
+        }
+    }
+
+    fn models_mut(&mut self) -> impl Iterator<Item = &mut MarkovIterator<String>> {
+        std::iter::once(&mut self.default).chain(self.languages.values_mut())
+    }
+
+    /// Give every chain its own RNG, seeded from `rng`.
+    pub fn set_rng(&mut self, rng: &mut impl Rng) {
+        for markov in self.models_mut() {
+            markov.set_rng(StdRng::from_rng(rng));
+        }
+    }
+
+    pub fn set_sampling(&mut self, sampling: Sampling) {
+        for markov in self.models_mut() {
+            markov.set_sampling(sampling);
+        }
+    }
+
+    /// Write every chain to `writer` in the versioned quixotic model format: the default chain as
+    /// written by [`MarkovIterator::save`], then the number of languages and, for each, its
+    /// language tag and chain.
+    pub fn save(&self, mut writer: impl Write) -> Result<(), Error> {
+        write_header(&mut writer)?;
+        self.default.write_chain(&mut writer)?;
+
+        write_u32(&mut writer, to_u32(self.languages.len())?)?;
+        for (lang, markov) in &self.languages {
+            write_str(&mut writer, lang)?;
+            markov.write_chain(&mut writer)?;
+        }
+
+        writer.flush()
+    }
+
+    /// Read models previously written by [`LanguageModels::save`] or [`MarkovIterator::save`].
+    pub fn load(mut reader: impl Read) -> Result<LanguageModels, Error> {
+        read_header(&mut reader)?;
+        let mut models = LanguageModels::from(MarkovIterator::read_chain(&mut reader)?);
+
+        let n_languages = read_u32(&mut reader)?;
+        for _ in 0..n_languages {
+            let lang = read_str(&mut reader)?;
+            let markov = MarkovIterator::read_chain(&mut reader)?;
+            if models.languages.insert(lang, markov).is_some() {
+                return Err(invalid_model("duplicate language"));
+            }
+        }
+
+        Ok(models)
+    }
+}
+
+/// Train a chain for each language on the `.html` and `.txt` files under `input`, along with a
+/// default chain trained on all of them.
+pub fn train(input: String, order: usize) -> Result<LanguageModels, TrainError> {
+    let mut trainer = Trainer::new(order);
+    trainer.add_dir(&input)?;
+    trainer.finish_languages()
 }
 
 #[cfg(test)]
@@ -199,10 +1283,10 @@ mod tests {
 
                 let word: String = word
                     .chars()
-                    .filter_map(|x| match x {
-                        ',' | '.' | '!' | '?' | ':' | ';' => Some(x),
-                        '\n' | '\r' | '"' | '\'' => None,
-                        _ => Some(x),
+                    .filter(|x| match x {
+                        ',' | '.' | '!' | '?' | ':' | ';' => true,
+                        '\n' | '\r' | '"' | '\'' => false,
+                        _ => true,
                     })
                     .collect();
 
@@ -210,12 +1294,430 @@ mod tests {
             }
         }
 
-        let mut res = MarkovIterator::new(tokens.into_iter());
+        let mut res = MarkovIterator::new(tokens.into_iter()).unwrap();
 
         for _ in 0..1_000_000 {
             let tok = res.next();
-            assert!(!tok.is_none());
+            assert!(tok.is_some());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn empty_and_tiny_corpora() {
+        let empty = MarkovIterator::new(Vec::<String>::new().into_iter());
+        assert!(matches!(empty, Err(TrainError::EmptyCorpus)));
+
+        let one_word = MarkovIterator::new(["hello".to_string()].into_iter());
+        assert!(matches!(
+            one_word,
+            Err(TrainError::CorpusTooSmall { tokens: 1 })
+        ));
+
+        let two_words = "hello world".split(' ').map(String::from);
+        assert!(matches!(
+            MarkovIterator::with_order(two_words, 2),
+            Err(TrainError::CorpusTooSmall { tokens: 2 })
+        ));
+    }
+
+    #[test]
+    fn higher_order() {
+        // With an order of 1, "b" may be followed by either "c" or "e"; an order 2 chain knows
+        // that "a b" is always followed by "c" and "d b" by "e".
+        let corpus = "a b c d b e a b c d b e a b";
+        let tokens = corpus.split(' ').map(String::from);
+
+        let mut res = MarkovIterator::with_order(tokens, 2).unwrap();
+        assert_eq!(res.order(), 2);
+
+        let generated = res.n_tokens(1000, &mut rand::rng());
+        assert_eq!(generated.len(), 1000);
+
+        let streamed = (0..1000).filter_map(|_| res.next()).collect::<Vec<_>>();
+
+        for output in [generated, streamed] {
+            for window in output.windows(3) {
+                match window[0].as_str() {
+                    "a" => assert_eq!(window[2].as_str(), "c"),
+                    "d" => assert_eq!(window[2].as_str(), "e"),
+                    _ => {}
+                }
+            }
         }
+    }
+
+    #[test]
+    fn save_and_load() -> Result<(), std::io::Error> {
+        let corpus = "the quick brown fox jumped over the lazy dog and the quick cat";
+        let tokens = corpus.split(' ').map(String::from);
+        let markov = MarkovIterator::with_order(tokens, 2).unwrap();
+
+        let mut buf = vec![];
+        markov.save(&mut buf)?;
+
+        let mut loaded = MarkovIterator::load(&buf[..])?;
+        assert_eq!(loaded.order(), 2);
+        assert_eq!(loaded.chain.symbols, markov.chain.symbols);
+        assert_eq!(loaded.chain.states, markov.chain.states);
+        assert_eq!(loaded.chain.offsets, markov.chain.offsets);
+        assert_eq!(loaded.chain.edges, markov.chain.edges);
+        assert!(loaded.next().is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn load_rejects_other_versions() -> Result<(), std::io::Error> {
+        let tokens = "a b c".split(' ').map(String::from);
+        let mut buf = vec![];
+        MarkovIterator::new(tokens).unwrap().save(&mut buf)?;
+        buf[4..8].copy_from_slice(&(MODEL_VERSION + 1).to_le_bytes());
+
+        let Err(e) = MarkovIterator::load(&buf[..]) else {
+            panic!("loaded a model with the wrong format version");
+        };
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert!(e.to_string().contains("version"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn load_rejects_duplicate_symbols() -> Result<(), std::io::Error> {
+        // An order 1 chain with the symbols ["a", "a"] and a single state, "a" -> "a".
+        let mut buf = vec![];
+        write_header(&mut buf)?;
+        write_u32(&mut buf, 1)?;
+        write_u32(&mut buf, 2)?;
+        write_str(&mut buf, "a")?;
+        write_str(&mut buf, "a")?;
+        write_u32(&mut buf, 1)?;
+        write_u32(&mut buf, 1)?;
+        write_u32(&mut buf, 1)?;
+        write_u32(&mut buf, 1)?;
+        write_u32(&mut buf, 1)?;
+
+        let Err(e) = MarkovIterator::load(&buf[..]) else {
+            panic!("loaded a model with a duplicate symbol");
+        };
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert!(e.to_string().contains("duplicate symbol"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn seeded_output_is_reproducible() {
+        let corpus = "the quick brown fox jumped over the lazy dog and the quick cat ate the fox";
+        let build = || {
+            let mut markov = MarkovIterator::new(corpus.split(' ').map(String::from)).unwrap();
+            markov.set_rng(StdRng::seed_from_u64(42));
+            markov
+        };
+
+        let (mut a, mut b) = (build(), build());
+        let a_tokens = a.n_tokens(100, &mut StdRng::seed_from_u64(7));
+        let b_tokens = b.n_tokens(100, &mut StdRng::seed_from_u64(7));
+        assert_eq!(a_tokens, b_tokens);
+
+        for _ in 0..100 {
+            assert_eq!(a.next(), b.next());
+        }
+    }
+
+    #[test]
+    fn weighted_transitions() {
+        let corpus = "a b a b a b a c a";
+        let mut markov = MarkovIterator::new(corpus.split(' ').map(String::from)).unwrap();
+
+        // Repeated transitions are stored once, with a count.
+        assert_eq!(markov.chain.symbols.len(), 3);
+        assert_eq!(markov.chain.edges.len(), 4);
+
+        // "a" is followed by "b" three times as often as by "c".
+        markov.set_rng(StdRng::seed_from_u64(1));
+        let (mut b, mut c) = (0, 0);
+        let mut previous = None;
+        for token in markov.take(100_000) {
+            if previous.as_deref().map(String::as_str) == Some("a") {
+                match token.as_str() {
+                    "b" => b += 1,
+                    "c" => c += 1,
+                    _ => {}
+                }
+            }
+            previous = Some(token);
+        }
+
+        let ratio = b as f64 / c as f64;
+        assert!((2.8..3.2).contains(&ratio), "b:c ratio was {ratio}");
+    }
+
+    #[test]
+    fn sampling_controls() {
+        let corpus = "a b a b a b a c a";
+        let mut markov = MarkovIterator::new(corpus.split(' ').map(String::from)).unwrap();
+        markov.set_rng(StdRng::seed_from_u64(1));
+
+        // At temperature 0, or with only the top transition allowed, "a" is always followed by
+        // its most common successor.
+        for sampling in [
+            Sampling {
+                temperature: 0.0,
+                ..Default::default()
+            },
+            Sampling {
+                top_k: Some(1),
+                ..Default::default()
+            },
+        ] {
+            markov.set_sampling(sampling);
+            let tokens = markov.n_tokens(1000, &mut StdRng::seed_from_u64(2));
+            for pair in tokens.windows(2) {
+                if pair[0].as_str() == "a" {
+                    assert_eq!(pair[1].as_str(), "b");
+                }
+            }
+        }
+
+        // A low temperature strongly favours the most common transition, however large the
+        // counts.
+        let edges = [
+            Edge {
+                token: 1,
+                next: NO_STATE,
+                cumulative: 1000,
+            },
+            Edge {
+                token: 2,
+                next: NO_STATE,
+                cumulative: 1003,
+            },
+        ];
+        let cold = Sampling {
+            temperature: 0.001,
+            ..Default::default()
+        };
+        let mut rng = StdRng::seed_from_u64(4);
+        for _ in 0..1000 {
+            assert_eq!(cold.choose(&edges, &mut rng).token, 1);
+        }
+
+        // A restart after every token still generates the requested number of tokens.
+        markov.set_sampling(Sampling {
+            restart_probability: 1.0,
+            ..Default::default()
+        });
+        assert_eq!(
+            markov.n_tokens(1000, &mut StdRng::seed_from_u64(3)).len(),
+            1000
+        );
+    }
+
+    #[test]
+    fn sentences() -> Result<(), std::io::Error> {
+        let corpus = [
+            "the cat sat on the mat.",
+            "a dog barked at the cat, loudly!",
+            "did the dog sleep",
+        ];
+        let sentences = corpus.iter().map(|s| s.split(' ').map(String::from));
+        let mut markov = MarkovIterator::from_sentences(sentences, 1).unwrap();
+        let mut rng = StdRng::seed_from_u64(1);
+
+        for sentence in markov.sentences(200, &mut rng) {
+            let first = sentence.chars().next().unwrap();
+            assert!(first.is_uppercase(), "{sentence:?} is not capitalised");
+            assert!(
+                ends_sentence(&sentence),
+                "{sentence:?} does not end in punctuation"
+            );
+            assert!(!sentence.ends_with(",."));
+        }
+
+        // Boundaries steer generation but are never emitted, and survive a save and load.
+        let mut buf = vec![];
+        markov.save(&mut buf)?;
+        let loaded = MarkovIterator::load(&buf[..])?;
+        assert_eq!(loaded.chain.start, markov.chain.start);
+        assert_eq!(loaded.chain.edges, markov.chain.edges);
+
+        markov.set_rng(StdRng::seed_from_u64(2));
+        let words = corpus.join(" ");
+        for token in markov.take(1000) {
+            assert!(words.split(' ').any(|word| word == token.as_str()));
+        }
+
+        let paragraph = loaded.paragraph(&mut rng);
+        assert!((3..=7).contains(&paragraph.matches(['.', '!', '?']).count()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn context() -> Result<(), TrainError> {
+        let mut trainer = Trainer::new(2);
+        trainer.add_text("The cat sat on the mat. The dog ran to the cat. A dog sat on a log.");
+        let mut markov = trainer.finish()?;
+        markov.set_rng(StdRng::seed_from_u64(1));
+        let words = |text: &str| text.split(' ').map(String::from).collect::<Vec<_>>();
+
+        // Only the last `order` words count, and sentences start from boundaries.
+        for _ in 0..20 {
+            let next = markov.next_in_context(&words("ran to the"), None, |_| false);
+            assert_eq!(next.as_str(), "cat.");
+            let first = markov.next_in_context(&[], None, |_| false);
+            assert!(["The", "A"].contains(&first.as_str()), "{first}");
+        }
+
+        // Tokens that can lead into the following word are preferred.
+        let ran = String::from("ran");
+        for _ in 0..20 {
+            let next = markov.next_in_context(&words("The"), Some(&ran), |_| false);
+            assert_eq!(next.as_str(), "dog");
+        }
+
+        // Avoided tokens are only sampled when nothing else can follow.
+        for _ in 0..20 {
+            let next = markov.next_in_context(&words("sat on"), None, |token| token == "the");
+            assert_eq!(next.as_str(), "a");
+            let next = markov.next_in_context(&words("ran to"), None, |token| token == "the");
+            assert_eq!(next.as_str(), "the");
+        }
+
+        // Unseen contexts carry on the walk, and merging rebuilds the index.
+        let _ = markov.next_in_context(&words("no such"), None, |_| false);
+        markov.merge(&MarkovIterator::from_sentences([words("The owl hooted.")], 2).unwrap())?;
+        for _ in 0..20 {
+            let next = markov.next_in_context(&words("The owl"), None, |_| false);
+            assert_eq!(next.as_str(), "hooted.");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn trainer() -> Result<(), TrainError> {
+        let text = "The cat sat on the mat. The dog\nsat on the cat!\nNo end";
+
+        let mut from_text = Trainer::new(1);
+        from_text.add_text(text);
+        let from_text = from_text.finish()?;
+
+        let mut from_reader = Trainer::new(1);
+        from_reader.add_reader(text.as_bytes())?;
+        let from_reader = from_reader.finish()?;
+
+        assert_eq!(from_text.chain.symbols, from_reader.chain.symbols);
+        assert_eq!(from_text.chain.edges, from_reader.chain.edges);
+
+        let mut from_html = Trainer::new(1);
+        from_html.add_html(
+            "<html><head><script>var cat = 1;</script><style>p { color: red }</style></head>\
+             <body><p>The cat sat on the mat.</p><pre><code>cat mat</code> done</pre></body></html>",
+        );
+        assert_eq!(from_html.finish()?.chain.symbols.len(), 6);
+
+        // Block elements end sentences, inline ones don't.
+        let mut blocks = Trainer::new(1);
+        blocks.add_html("<h1>Welcome</h1><ul><li>The <b>cat</b> sat</li><li>Done</li></ul>");
+        let blocks = blocks.finish()?;
+        let sentences = ["Welcome", "The cat sat", "Done"].map(|s| s.split(' ').map(String::from));
+        let expected = MarkovIterator::from_sentences(sentences, 1)?;
+        assert_eq!(blocks.chain.symbols, expected.chain.symbols);
+        assert_eq!(blocks.chain.edges, expected.chain.edges);
+
+        let mut code = Trainer::new(1);
+        code.set_skip_elements(HashSet::from([String::from("script")]));
+        code.add_html("<p>Some <code>literal code</code> text.</p><script>var x;</script>");
+        assert_eq!(code.finish()?.chain.symbols.len(), 4);
+
+        let mut trainer = Trainer::new(1);
+        let Err(TrainError::Io(_)) = trainer.add_reader(&[0xff, 0xfe][..]) else {
+            panic!("read invalid UTF-8");
+        };
+
+        Ok(())
+    }
+
+    #[test]
+    fn merge_and_update() -> Result<(), TrainError> {
+        let first = "The cat sat on the mat. The dog sat on the cat!";
+        let second = "A dog sat on a log. The cat ran.";
+
+        let mut both = Trainer::new(2);
+        both.add_text(first);
+        both.add_text(second);
+        let both = both.finish()?;
+
+        let train = |text: &str| {
+            let mut trainer = Trainer::new(2);
+            trainer.add_text(text);
+            trainer.finish()
+        };
+
+        let mut merged = train(first)?;
+        merged.merge(&train(second)?)?;
+        assert_eq!(merged.chain.symbols, both.chain.symbols);
+        assert_eq!(merged.chain.states, both.chain.states);
+        assert_eq!(merged.chain.edges, both.chain.edges);
+        assert_eq!(merged.chain.start, both.chain.start);
+
+        let mut updated = Trainer::update(&train(first)?.into());
+        updated.add_text(second);
+        let updated = updated.finish()?;
+        assert_eq!(updated.chain.symbols, both.chain.symbols);
+        assert_eq!(updated.chain.edges, both.chain.edges);
+
+        let Err(TrainError::OrderMismatch {
+            expected: 2,
+            found: 1,
+        }) = merged.merge(&MarkovIterator::new("a b c".split(' ').map(String::from))?)
+        else {
+            panic!("merged chains of different orders");
+        };
+
+        Ok(())
+    }
+
+    #[test]
+    fn language_models() -> Result<(), Box<dyn std::error::Error>> {
+        let mut trainer = Trainer::new(1);
+        trainer.add_html(
+            "<html lang=en><body><p>The cat sat on the mat.</p>\
+             <p lang=de-DE>Die Katze sitzt auf der Matte.</p></body></html>",
+        );
+        trainer.add_html("<html lang=es><body><p>El gato se sienta.</p></body></html>");
+        trainer.add_text("No language at all here.");
+        let models = trainer.finish_languages()?;
+
+        assert_eq!(models.languages().collect::<Vec<_>>(), ["de", "en", "es"]);
+        let mut rng = StdRng::seed_from_u64(1);
+        let german = ["Die", "Katze", "sitzt", "auf", "der", "Matte."];
+        for token in models.get(Some("de-AT")).n_tokens(100, &mut rng) {
+            assert!(german.contains(&token.as_str()), "{token} is not German");
+        }
+        assert_eq!(models.get(Some("fr")).order(), 1);
+        assert_eq!(
+            models.get(None).chain.symbols.len(),
+            models.default_model().chain.symbols.len()
+        );
+
+        let mut buf = vec![];
+        models.save(&mut buf)?;
+        let loaded = LanguageModels::load(&buf[..])?;
+        assert_eq!(
+            loaded.languages().collect::<Vec<_>>(),
+            models.languages().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            loaded.get(Some("es")).chain.edges,
+            models.get(Some("es")).chain.edges
+        );
+        let default = MarkovIterator::load(&buf[..])?;
+        assert_eq!(default.chain.edges, models.default_model().chain.edges);
 
         Ok(())
     }
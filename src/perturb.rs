@@ -0,0 +1,355 @@
+// Copyright 2024 Marcus Butler <marcusb@marcusb.org>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Perturbing the facts in a text: numbers, dates and names are swapped for plausible but wrong
+//! values of the same shape, e.g. `$12.50` for `$38.75`, `2024-03-28` for `2023-11-05`, and
+//! `Alice` for `Ogimu`.
+
+use rand::{seq::IndexedRandom, Rng};
+
+use crate::replace::split_word;
+use crate::tokenize::primary_language;
+
+const MONTHS: [&str; 12] = [
+    "january",
+    "february",
+    "march",
+    "april",
+    "may",
+    "june",
+    "july",
+    "august",
+    "september",
+    "october",
+    "november",
+    "december",
+];
+
+const VOWELS: [char; 5] = ['a', 'e', 'i', 'o', 'u'];
+const CONSONANTS: [char; 16] = [
+    'b', 'd', 'f', 'g', 'h', 'k', 'l', 'm', 'n', 'p', 'r', 's', 't', 'v', 'w', 'z',
+];
+
+/// How often each kind of fact is perturbed, from 0.0 (never) to 1.0 (always).
+#[derive(Clone, Debug, Default)]
+pub struct PerturbRates {
+    pub numbers: f32,
+    pub dates: f32,
+    pub names: f32,
+}
+
+impl PerturbRates {
+    /// Whether any kind of fact is perturbed at all.
+    pub fn any(&self) -> bool {
+        self.numbers > 0.0 || self.dates > 0.0 || self.names > 0.0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Fact {
+    Number,
+    Date,
+    Name,
+}
+
+/// Languages that capitalise every noun, so capitalisation says nothing about what is a name.
+const NOUN_CAPITALISING_LANGUAGES: [&str; 2] = ["de", "lb"];
+
+/// What kind of fact, if any, the core of a word in `lang` is.  Capitalised words are names
+/// unless they start a sentence, where only those followed by another capitalised word are.
+/// Nothing is a name in languages that capitalise nouns.
+fn classify(
+    core: &str,
+    sentence_start: bool,
+    next: Option<&str>,
+    lang: Option<&str>,
+) -> Option<Fact> {
+    let names = !lang
+        .is_some_and(|lang| NOUN_CAPITALISING_LANGUAGES.contains(&primary_language(lang).as_str()));
+    if date_groups(core).is_some() || month(core).is_some() {
+        Some(Fact::Date)
+    } else if is_number(core) {
+        Some(Fact::Number)
+    } else if names
+        && is_capitalised(core)
+        && !is_i_contraction(core)
+        && (!sentence_start || next.is_some_and(is_capitalised))
+    {
+        Some(Fact::Name)
+    } else {
+        None
+    }
+}
+
+/// A wrong value of the same shape for `word`, if it is a fact chosen at random to be perturbed
+/// at the rate for its kind.  `sentence_start` is whether the word starts a sentence, `next` is
+/// the word after it, if any, and `lang` is the language of the text.
+pub fn perturb<R: Rng>(
+    word: &str,
+    sentence_start: bool,
+    next: Option<&str>,
+    lang: Option<&str>,
+    rates: &PerturbRates,
+    rng: &mut R,
+) -> Option<String> {
+    let (prefix, core, suffix) = split_word(word);
+    let next = next.map(|next| split_word(next).1);
+    let fact = classify(core, sentence_start, next, lang)?;
+    let rate = match fact {
+        Fact::Number => rates.numbers,
+        Fact::Date => rates.dates,
+        Fact::Name => rates.names,
+    };
+    if rate <= 0.0 || rng.random::<f32>() >= rate {
+        return None;
+    }
+
+    let perturbed = |rng: &mut R| match fact {
+        Fact::Number => perturb_number(core, rng),
+        Fact::Date => perturb_date(core, rng),
+        Fact::Name => perturb_name(core, rng),
+    };
+    // A few tries to avoid choosing the original value again by chance.
+    let mut value = perturbed(rng);
+    for _ in 0..4 {
+        if value != core {
+            break;
+        }
+        value = perturbed(rng);
+    }
+    Some(format!("{prefix}{value}{suffix}"))
+}
+
+fn is_number(core: &str) -> bool {
+    core.starts_with(|c: char| c.is_ascii_digit())
+        && core.ends_with(|c: char| c.is_ascii_digit())
+        && core
+            .chars()
+            .all(|c| c.is_ascii_digit() || c == ',' || c == '.')
+}
+
+fn is_capitalised(core: &str) -> bool {
+    let mut chars = core.chars();
+    chars.next().is_some_and(char::is_uppercase)
+        && core.chars().count() > 1
+        && chars.all(|c| c.is_alphabetic() || c == '-' || c == '\'' || c == '’')
+}
+
+/// Whether `core` is a contraction of "I", such as "I'm" or "I'll".
+fn is_i_contraction(core: &str) -> bool {
+    core.strip_prefix('I')
+        .is_some_and(|rest| rest.starts_with(['\'', '’']))
+}
+
+/// The three groups of digits in a numeric date such as `2024-03-28`, `28/03/2024` or `3.28.24`,
+/// and the separator between them.
+fn date_groups(core: &str) -> Option<([&str; 3], char)> {
+    let separator = core.chars().find(|c| matches!(c, '-' | '/' | '.'))?;
+    let mut groups = core.split(separator);
+    let groups = [groups.next()?, groups.next()?, groups.next()?];
+    if core.split(separator).count() != 3
+        || groups
+            .iter()
+            .any(|group| group.is_empty() || !group.chars().all(|c| c.is_ascii_digit()))
+    {
+        return None;
+    }
+
+    let lengths = groups.map(str::len);
+    match lengths {
+        [4, 1..=2, 1..=2] | [1..=2, 1..=2, 2 | 4] => Some((groups, separator)),
+        _ => None,
+    }
+}
+
+/// The index of the month named by `core`, in full or abbreviated to three letters or `Sept`, if
+/// it is capitalised.
+fn month(core: &str) -> Option<usize> {
+    if !core.starts_with(char::is_uppercase) || core.len() < 3 {
+        return None;
+    }
+    let lower = core.trim_end_matches('.').to_lowercase();
+    MONTHS.iter().position(|month| {
+        *month == lower || ((lower.len() == 3 || lower == "sept") && month.starts_with(&lower))
+    })
+}
+
+/// Random digits in place of those in `core`, keeping its separators and leading digit non-zero.
+/// Four digit numbers that look like years are moved by up to ten years instead.
+fn perturb_number(core: &str, rng: &mut impl Rng) -> String {
+    if let Ok(year @ 1000..=2999) = core.parse::<i32>() {
+        let offset = rng.random_range(1..=10) * if rng.random() { 1 } else { -1 };
+        return (year + offset).to_string();
+    }
+
+    let mut first = true;
+    core.chars()
+        .map(|c| match c.to_digit(10) {
+            Some(digit) => {
+                let low = if first && digit > 0 { 1 } else { 0 };
+                first = false;
+                char::from_digit(rng.random_range(low..10), 10).unwrap_or(c)
+            }
+            None => c,
+        })
+        .collect()
+}
+
+/// Another date in the same format as `core`.
+fn perturb_date(core: &str, rng: &mut impl Rng) -> String {
+    if let Some(index) = month(core) {
+        let other = (index + rng.random_range(1..MONTHS.len())) % MONTHS.len();
+        let name = MONTHS[other];
+        let abbreviated = core.trim_end_matches('.').len() < MONTHS[index].len();
+        let name = if abbreviated { &name[..3] } else { name };
+        let upper = core.chars().all(|c| !c.is_lowercase());
+
+        let mut out = String::with_capacity(core.len());
+        for (i, c) in name.chars().enumerate() {
+            if upper || i == 0 {
+                out.extend(c.to_uppercase());
+            } else {
+                out.push(c);
+            }
+        }
+        if core.ends_with('.') {
+            out.push('.');
+        }
+        return out;
+    }
+
+    let Some((groups, separator)) = date_groups(core) else {
+        return core.to_string();
+    };
+    let values = groups
+        .iter()
+        .enumerate()
+        .map(|(i, group)| {
+            let value = group.parse::<u32>().unwrap_or_default();
+            let year = group.len() == 4 || (i == 2 && groups[0].len() < 4);
+            let new = match group.len() {
+                4 => value.saturating_add_signed(rng.random_range(-5..=5)),
+                _ if year => (value + rng.random_range(1..10)) % 100,
+                1 => rng.random_range(1..=9),
+                // Days and months below 13 are valid either way round, so dates whose order is
+                // ambiguous stay valid.
+                _ => rng.random_range(1..=12),
+            };
+            let width = group.len();
+            format!("{new:0width$}")
+        })
+        .collect::<Vec<_>>();
+    values.join(&separator.to_string())
+}
+
+/// A made-up name in place of `core`, with vowels and consonants in the same places.
+fn perturb_name(core: &str, rng: &mut impl Rng) -> String {
+    core.chars()
+        .map(|c| {
+            let lower = c.to_lowercase().next().unwrap_or(c);
+            let new = if VOWELS.contains(&lower) {
+                *VOWELS.choose(rng).unwrap_or(&lower)
+            } else if lower.is_ascii_alphabetic() {
+                *CONSONANTS.choose(rng).unwrap_or(&lower)
+            } else {
+                return c;
+            };
+            if c.is_uppercase() {
+                new.to_ascii_uppercase()
+            } else {
+                new
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seeded_rng;
+
+    #[test]
+    fn classification() {
+        assert_eq!(classify("12.50", false, None, None), Some(Fact::Number));
+        assert_eq!(classify("1,234", true, None, None), Some(Fact::Number));
+        assert_eq!(classify("2024-03-28", false, None, None), Some(Fact::Date));
+        assert_eq!(classify("28/3/24", false, None, None), Some(Fact::Date));
+        assert_eq!(classify("March", true, None, None), Some(Fact::Date));
+        assert_eq!(classify("Paris", false, None, None), Some(Fact::Name));
+        assert_eq!(
+            classify("Alice", true, Some("Smith"), None),
+            Some(Fact::Name)
+        );
+        assert_eq!(classify("The", true, Some("cat"), None), None);
+        assert_eq!(classify("I", false, None, None), None);
+        assert_eq!(classify("may", false, None, None), None);
+        assert_eq!(classify("1.2.3.4", false, None, None), Some(Fact::Number));
+        assert_eq!(classify("Sept", false, None, None), Some(Fact::Date));
+        assert_eq!(classify("I'm", false, None, None), None);
+        assert_eq!(classify("I’ll", false, None, None), None);
+        assert_eq!(classify("Katze", false, None, Some("de-AT")), None);
+        assert_eq!(classify("Berlin", false, None, Some("de")), None);
+        assert_eq!(classify("Paris", false, None, Some("fr")), Some(Fact::Name));
+    }
+
+    #[test]
+    fn perturbation() {
+        let mut rng = seeded_rng(Some(0));
+        let all = PerturbRates {
+            numbers: 1.0,
+            dates: 1.0,
+            names: 1.0,
+        };
+
+        for _ in 0..20 {
+            let price = perturb("$12.50!", false, None, None, &all, &mut rng).unwrap();
+            assert!(price.starts_with('$') && price.ends_with('!'), "{price}");
+            assert_eq!(price.len(), 7);
+            assert_ne!(price, "$12.50!");
+
+            let date = perturb("2024-03-28", false, None, None, &all, &mut rng).unwrap();
+            let (groups, _) = date_groups(&date).unwrap();
+            assert_eq!(groups.map(str::len), [4, 2, 2]);
+            assert!(
+                (1..=12).contains(&groups[1].parse::<u32>().unwrap()),
+                "{date}"
+            );
+
+            let year = perturb("1999", false, None, None, &all, &mut rng).unwrap();
+            assert!((1989..=2009).contains(&year.parse::<u32>().unwrap()));
+
+            let name = perturb("(Alice)", false, None, None, &all, &mut rng).unwrap();
+            assert_eq!(name.len(), 7);
+            assert!(name.starts_with('(') && name[1..].starts_with(char::is_uppercase));
+
+            let month = perturb("Mar.", false, None, None, &all, &mut rng).unwrap();
+            assert!(month.ends_with('.') && month.len() == 4 && month != "Mar.");
+
+            let month = perturb("Sept.", false, None, None, &all, &mut rng).unwrap();
+            assert!(month.ends_with('.') && month.len() == 4, "{month}");
+        }
+
+        assert_eq!(perturb("cat", false, None, None, &all, &mut rng), None);
+        assert_eq!(
+            perturb("42", false, None, None, &PerturbRates::default(), &mut rng),
+            None
+        );
+    }
+}
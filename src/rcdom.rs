@@ -42,9 +42,13 @@ use std::collections::{HashSet, VecDeque};
 use std::default::Default;
 use std::fmt;
 use std::io;
+use std::iter;
 use std::mem;
 use std::rc::{Rc, Weak};
+use std::str::FromStr;
 
+use rand::{rngs::StdRng, Rng};
+use serde_json::Value;
 use tendril::{fmt::UTF8, StrTendril, Tendril};
 
 use html5ever::driver::ParseOpts;
@@ -59,8 +63,12 @@ use markup5ever::serialize::{Serialize, Serializer};
 use markup5ever::Attribute;
 use markup5ever::ExpandedName;
 use markup5ever::QualName;
+use markup5ever::{LocalName, Namespace};
 
-use crate::markov::MarkovIterator;
+use crate::markov::{LanguageModels, MarkovIterator};
+use crate::replace::{scramble, Flow, Passage, ReplaceOptions};
+use crate::rules::Rules;
+use crate::tokenize::{join_words, Segment, Tokenizer};
 
 /// The different kinds of nodes in the DOM.
 #[derive(Debug)]
@@ -133,7 +141,7 @@ impl Drop for Node {
         let mut nodes = mem::take(&mut *self.children.borrow_mut());
         while let Some(node) = nodes.pop() {
             let children = mem::take(&mut *node.children.borrow_mut());
-            nodes.extend(children.into_iter());
+            nodes.extend(children);
             if let NodeData::Element {
                 ref template_contents,
                 ..
@@ -446,43 +454,258 @@ impl Default for RcDom {
 enum SerializeOp {
     Open(Handle),
     Close(QualName),
+    /// Write this many links into the linkmaze.
+    Linkmaze(usize),
+    /// Write this text.
+    Text(String),
+}
+
+/// Elements whose text is left alone by default: scripts, styles, code and form fields.
+pub const SKIP_ELEMENTS: [&str; 6] = ["script", "style", "pre", "code", "textarea", "noscript"];
+
+pub fn default_skip_elements() -> HashSet<String> {
+    SKIP_ELEMENTS.iter().map(|name| name.to_string()).collect()
+}
+
+/// Elements that don't break up the run of text around them, so sentences carry on through them.
+const INLINE_ELEMENTS: [&str; 28] = [
+    "a", "abbr", "b", "bdi", "bdo", "cite", "code", "data", "del", "dfn", "em", "font", "i", "ins",
+    "kbd", "mark", "q", "s", "samp", "small", "span", "strong", "sub", "sup", "time", "u", "var",
+    "wbr",
+];
+
+fn is_inline(name: &QualName) -> bool {
+    INLINE_ELEMENTS.contains(&&*name.local)
+}
+
+/// How links into the linkmaze are hidden from human visitors.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LinkStyle {
+    /// An ordinary link.
+    #[default]
+    Visible,
+    /// A link moved off screen with CSS, and hidden from screen readers and keyboard navigation.
+    Hidden,
+    /// A link inside `<noscript>`, which browsers with scripting enabled don't show.
+    Noscript,
+    /// A link inside `<template>`, which browsers never show.
+    Template,
+}
+
+impl FromStr for LinkStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "visible" => Ok(LinkStyle::Visible),
+            "hidden" => Ok(LinkStyle::Hidden),
+            "noscript" => Ok(LinkStyle::Noscript),
+            "template" => Ok(LinkStyle::Template),
+            _ => Err(format!(
+                "unknown link style {s:?} (expected \"visible\", \"hidden\", \"noscript\" or \"template\")"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for LinkStyle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkStyle::Visible => write!(f, "visible"),
+            LinkStyle::Hidden => write!(f, "hidden"),
+            LinkStyle::Noscript => write!(f, "noscript"),
+            LinkStyle::Template => write!(f, "template"),
+        }
+    }
 }
 
 pub struct HandleOptions {
     pub percent: f32,
     pub embed_linkmaze: bool,
     pub linkmaze_path: Option<String>,
+    /// Scatter this many links into the linkmaze among the page's paragraphs, rather than adding
+    /// one at the start of the body.  Paragraphs whose text is left alone never get links, and
+    /// pages without any other paragraphs get them at the start of the body.
+    pub linkmaze_links: Option<usize>,
+    pub linkmaze_style: LinkStyle,
+    /// The fraction of links to other pages on the site that are rewritten to lead into the
+    /// linkmaze instead, keeping their original target in [`ORIGINAL_HREF_ATTR`].  Links are only
+    /// rewritten when `linkmaze_path` is set, and only relative links count as being to the site.
+    pub rewrite_links: f32,
+    /// A canary phrase identifying the page, added to the end of a random paragraph whose text isn't
+    /// left alone, or to the start of the body if there is none.
+    pub canary: Option<String>,
+    /// Names of elements whose text, including that of their descendants, is never replaced.
+    pub skip_elements: HashSet<String>,
+    /// Replacement rates for the elements matching each rule, overriding `percent`.
+    pub rules: Rules,
+    /// Names of attributes, such as `alt` and `title`, whose values are replaced like text.
+    pub scramble_attrs: HashSet<String>,
+    /// Names of `<meta>` elements, from their `name` or `property` attribute, whose `content` is
+    /// replaced like text.  Names ending in `*` match any name with that prefix, e.g. `og:*`.
+    /// Content that is a URL or a single word, such as that of `og:image` or `og:type`, is left
+    /// alone.
+    pub scramble_meta: HashSet<String>,
+    /// Keys, such as `articleBody`, whose string values are replaced like text in JSON-LD
+    /// `<script>` elements.
+    pub json_ld_keys: HashSet<String>,
+    /// How replacement words are chosen and fitted into the text.
+    pub replace: ReplaceOptions,
 }
 
 pub struct TokenizeHandle(pub Handle);
 pub struct SerializableHandle<'a>(
     Handle,
-    RefCell<&'a mut MarkovIterator<String>>,
+    RefCell<&'a mut LanguageModels>,
     HandleOptions,
+    RefCell<&'a mut StdRng>,
+    &'a dyn Tokenizer,
 );
 
-impl SerializableHandle<'_> {
+impl<'a> SerializableHandle<'a> {
     pub fn new(
         h: Handle,
-        markov: &mut MarkovIterator<String>,
-        percent: f32,
-        embed_linkmaze: bool,
-        linkmaze_path: Option<String>,
-    ) -> SerializableHandle<'_> {
+        markov: &'a mut LanguageModels,
+        rng: &'a mut StdRng,
+        tokenizer: &'a dyn Tokenizer,
+        options: HandleOptions,
+    ) -> SerializableHandle<'a> {
         SerializableHandle(
             h,
             RefCell::new(markov),
-            HandleOptions {
-                percent,
-                embed_linkmaze,
-                linkmaze_path,
-            },
+            options,
+            RefCell::new(rng),
+            tokenizer,
         )
     }
 }
 
+/// The contents of a text node, and the language it is written in according to the nearest `lang`
+/// attribute.
+pub struct HtmlText {
+    pub lang: Option<String>,
+    pub text: String,
+    /// Whether a block element, such as a heading or paragraph, starts or ends between the
+    /// previous text node and this one, so that this one doesn't carry on its sentence.
+    pub block_start: bool,
+}
+
+/// Setting this attribute to `skip` leaves the element's text alone, as for `skip_elements`.
+pub const SKIP_ATTR: &str = "data-quixotic";
+
+/// This attribute sets the replacement rate, between 0.0 and 1.0, for the text of the element and
+/// its descendants, overriding any rules that match them.
+pub const RATE_ATTR: &str = "data-quixotic-rate";
+
+/// Links rewritten to lead into the linkmaze keep their original target in this attribute, so
+/// that scripts can restore it for human visitors.
+pub const ORIGINAL_HREF_ATTR: &str = "data-quixotic-href";
+
+/// Text between a comment reading `quixotic:off` and one reading `quixotic:on` is left alone.
+const OFF_MARKER: &str = "quixotic:off";
+const ON_MARKER: &str = "quixotic:on";
+
+/// The value of the attribute called `name`, if the element has it.
+fn attr<'a>(attrs: &'a [Attribute], name: &str) -> Option<&'a str> {
+    attrs
+        .iter()
+        .find(|attr| attr.name.local == *name)
+        .map(|attr| &attr.value[..])
+}
+
+/// The value of an element's `lang` attribute, if it has a non-empty one.
+fn lang_attr(attrs: &[Attribute]) -> Option<String> {
+    attr(attrs, "lang")
+        .map(|lang| lang.trim().to_string())
+        .filter(|lang| !lang.is_empty())
+}
+
+/// Whether an element is marked with [`SKIP_ATTR`] to have its text left alone.
+fn skip_attr(attrs: &[Attribute]) -> bool {
+    attr(attrs, SKIP_ATTR).is_some_and(|value| value.trim() == "skip")
+}
+
+/// The rate in an element's [`RATE_ATTR`] attribute, if it has a valid one.
+fn rate_attr(attrs: &[Attribute]) -> Option<f32> {
+    attr(attrs, RATE_ATTR)
+        .and_then(|rate| rate.trim().parse::<f32>().ok())
+        .filter(|rate| (0.0..=1.0).contains(rate))
+}
+
+/// Whether an attribute only marks up the element for us, and is left out of the output.
+fn is_marker_attr(attr: &Attribute) -> bool {
+    attr.name.local == *SKIP_ATTR || attr.name.local == *RATE_ATTR
+}
+
+/// Whether a comment turns replacement off (`Some(false)`) or back on (`Some(true)`).
+fn marker_comment(contents: &str) -> Option<bool> {
+    match contents.trim() {
+        OFF_MARKER => Some(false),
+        ON_MARKER => Some(true),
+        _ => None,
+    }
+}
+
+/// What applies to the text inside an element, inherited from its ancestors.
+#[derive(Clone, Default)]
+struct Scope {
+    lang: Option<String>,
+    /// Whether the element or an ancestor is one whose text is left alone.
+    skip: bool,
+    /// The replacement rate set by the rules, if any rule matched the element or an ancestor.
+    rate: Option<f32>,
+    /// The rate set by the nearest [`RATE_ATTR`] on the element or an ancestor, which takes
+    /// precedence over the rules for the whole subtree.
+    marker_rate: Option<f32>,
+}
+
+impl Scope {
+    /// Whether a rate of 0.0 excludes the text from being changed at all, including by
+    /// perturbation.
+    fn excluded(&self) -> bool {
+        self.rate == Some(0.0)
+    }
+
+    /// Whether the text may be changed, or have links and canaries added to it, given whether
+    /// marker comments have left changes `on`.
+    fn changeable(&self, on: bool) -> bool {
+        on && !self.skip && !self.excluded()
+    }
+}
+
+/// Push the scope of an element being opened, which inherits from its parent's.
+fn push_scope(
+    scopes: &mut Vec<Scope>,
+    element: &Handle,
+    skip_elements: &HashSet<String>,
+    rules: Option<&Rules>,
+) {
+    let NodeData::Element {
+        ref name,
+        ref attrs,
+        ..
+    } = element.data
+    else {
+        return;
+    };
+
+    let attrs = attrs.borrow();
+    let parent = scopes.last().cloned().unwrap_or_default();
+    let marker_rate = rate_attr(&attrs).or(parent.marker_rate);
+    scopes.push(Scope {
+        lang: lang_attr(&attrs).or(parent.lang),
+        skip: parent.skip || skip_elements.contains(&*name.local) || skip_attr(&attrs),
+        rate: marker_rate
+            .or_else(|| rules.and_then(|rules| rules.rate(element)))
+            .or(parent.rate),
+        marker_rate,
+    });
+}
+
 impl TokenizeHandle {
-    pub fn tokenize(dom: Handle) -> Vec<String> {
+    /// The contents of every text node under `dom`, in document order, except those inside
+    /// `skip_elements` or marked to be left alone.
+    pub fn text(dom: Handle, skip_elements: &HashSet<String>) -> Vec<HtmlText> {
         let mut ops = VecDeque::new();
         ops.extend(
             dom.children
@@ -491,12 +714,17 @@ impl TokenizeHandle {
                 .map(|h| SerializeOp::Open(h.clone())),
         );
 
-        let mut tokens = vec![];
+        let mut text = vec![];
+        let mut scopes = vec![];
+        let mut on = true;
+        let mut block_start = true;
 
         while let Some(op) = ops.pop_front() {
             match op {
                 SerializeOp::Open(handle) => match handle.data {
                     NodeData::Element { ref name, .. } => {
+                        block_start |= !is_inline(name);
+                        push_scope(&mut scopes, &handle, skip_elements, None);
                         ops.reserve(1 + handle.children.borrow().len());
                         ops.push_front(SerializeOp::Close(name.clone()));
 
@@ -506,34 +734,51 @@ impl TokenizeHandle {
                     }
 
                     NodeData::Text { ref contents } => {
-                        for word in contents.borrow().split(' ') {
-                            let word = word.trim();
-
-                            if word.contains('>') || word.contains('<') || word.is_empty() {
-                                continue;
-                            }
-
-                            if word.contains('\n') {
-                                tokens.extend(word.split('\n').filter_map(|x| {
-                                    if !x.is_empty() {
-                                        Some(String::from(x))
-                                    } else {
-                                        None
-                                    }
-                                }));
-                            } else {
-                                tokens.push(word.into());
-                            }
+                        let scope = scopes.last().cloned().unwrap_or_default();
+                        if on && !scope.skip {
+                            text.push(HtmlText {
+                                lang: scope.lang,
+                                text: contents.borrow().to_string(),
+                                block_start: mem::take(&mut block_start),
+                            });
                         }
                     }
 
+                    NodeData::Comment { ref contents } => {
+                        on = marker_comment(contents).unwrap_or(on);
+                    }
+
                     _ => {}
                 },
 
-                SerializeOp::Close(_name) => {}
+                SerializeOp::Close(name) => {
+                    block_start |= !is_inline(&name);
+                    scopes.pop();
+                }
+
+                SerializeOp::Linkmaze(_) | SerializeOp::Text(_) => {}
             }
         }
-        tokens
+        text
+    }
+
+    pub fn tokenize(
+        dom: Handle,
+        tokenizer: &dyn Tokenizer,
+        skip_elements: &HashSet<String>,
+    ) -> Vec<String> {
+        Self::text(dom, skip_elements)
+            .iter()
+            .flat_map(|node| {
+                tokenizer
+                    .segments_in(&node.text, node.lang.as_deref())
+                    .into_iter()
+                    .filter_map(|segment| match segment {
+                        Segment::Word(word) => Some(String::from(word)),
+                        Segment::Other(_) => None,
+                    })
+            })
+            .collect()
     }
 }
 
@@ -546,7 +791,35 @@ impl Serialize for SerializableHandle<'_> {
         let percent = self.2.percent;
         let embed_linkmaze = self.2.embed_linkmaze;
         let linkmaze_path = self.2.linkmaze_path.clone().unwrap_or_default();
-        let mut rng = rand::rng();
+        let mut rng = self.3.borrow_mut();
+        let mut scopes = vec![];
+        let mut on = true;
+        // Where the text has got to, carried through inline elements.
+        let mut flow = Flow::default();
+
+        // The number of links to add at the end of each changeable paragraph, in document order.
+        let rules = (!self.2.rules.is_empty()).then_some(&self.2.rules);
+        let n_paragraphs = count_paragraphs(
+            &self.0,
+            &mut vec![],
+            &mut true,
+            &self.2.skip_elements,
+            rules,
+        );
+        let mut paragraph_links = vec![];
+        let mut paragraphs = 0;
+        if let (true, Some(n_links)) = (embed_linkmaze, self.2.linkmaze_links) {
+            if n_paragraphs > 0 {
+                paragraph_links = vec![0; n_paragraphs];
+                for _ in 0..n_links {
+                    paragraph_links[rng.random_range(0..n_paragraphs)] += 1;
+                }
+            }
+        }
+        let canary_paragraph = match self.2.canary {
+            Some(_) if n_paragraphs > 0 => Some(rng.random_range(0..n_paragraphs)),
+            _ => None,
+        };
 
         let mut ops = VecDeque::new();
         match traversal_scope {
@@ -568,37 +841,146 @@ impl Serialize for SerializableHandle<'_> {
                         ref attrs,
                         ..
                     } => {
+                        if !is_inline(name) {
+                            flow = Flow::default();
+                        }
+                        let parent_skip = scopes.last().is_some_and(|scope: &Scope| scope.skip);
+                        push_scope(&mut scopes, &handle, &self.2.skip_elements, rules);
+
+                        let scope = scopes.last().cloned().unwrap_or_default();
+                        let keep = scope.rate.map_or(percent, |rate| 1.0 - rate);
+                        let replace = scope.changeable(on) && self.2.replace.changes_text(keep);
+
+                        let attrs = attrs.borrow();
+                        // JSON-LD is replaced even though scripts are usually skipped, unless it is
+                        // inside a skipped element or marked up itself.
+                        let json_ld = on
+                            && !parent_skip
+                            && !scope.excluded()
+                            && self.2.replace.changes_text(keep)
+                            && !self.2.json_ld_keys.is_empty()
+                            && !skip_attr(&attrs)
+                            && is_json_ld(name, &attrs);
+                        let meta = name.local == *"meta"
+                            && ["name", "property"].iter().any(|key| {
+                                attr(&attrs, key)
+                                    .is_some_and(|name| name_matches(&self.2.scramble_meta, name))
+                            })
+                            && attr(&attrs, "content").is_some_and(is_prose);
+                        let rewrite = name.local == *"a"
+                            && scope.changeable(on)
+                            && self.2.rewrite_links > 0.0
+                            && self.2.linkmaze_path.is_some()
+                            && attr(&attrs, "href").is_some_and(is_internal_link)
+                            && rng.random::<f32>() < self.2.rewrite_links;
+                        let original_href = rewrite.then(|| html_name(ORIGINAL_HREF_ATTR));
+
+                        let mut output_attrs = attrs
+                            .iter()
+                            .filter(|at| !is_marker_attr(at))
+                            .map(|at| {
+                                let selected = self.2.scramble_attrs.contains(&*at.name.local)
+                                    || (meta && at.name.local == *"content");
+                                let value = if rewrite && at.name.local == *"href" {
+                                    let link = crate::rand_link(&mut **rng);
+                                    Cow::Owned(format!("{linkmaze_path}/{link}.html"))
+                                } else if replace && selected {
+                                    Cow::Owned(scramble(
+                                        &at.value,
+                                        Passage {
+                                            lang: scope.lang.as_deref(),
+                                            keep,
+                                            flow: &mut Flow::default(),
+                                        },
+                                        &mut markov,
+                                        &mut rng,
+                                        self.4,
+                                        &self.2.replace,
+                                    ))
+                                } else {
+                                    Cow::Borrowed(&at.value[..])
+                                };
+                                (&at.name, value)
+                            })
+                            .collect::<Vec<_>>();
+                        if let Some(ref original_href) = original_href {
+                            let href = attr(&attrs, "href").unwrap_or_default();
+                            output_attrs.push((original_href, Cow::Borrowed(href)));
+                        }
                         serializer.start_elem(
                             name.clone(),
-                            attrs.borrow().iter().map(|at| (&at.name, &at.value[..])),
+                            output_attrs.iter().map(|(name, value)| (*name, &value[..])),
                         )?;
 
-                        use markup5ever::{LocalName, Namespace};
-
-                        if embed_linkmaze && name.local == *"body" {
-                            let link = crate::rand_link(&mut rng);
-                            serializer.start_elem(
-                                QualName::new(None, Namespace::from(""), LocalName::from("a")),
-                                vec![(
-                                    &QualName::new(
-                                        None,
-                                        Namespace::from(""),
-                                        LocalName::from("href"),
-                                    ),
-                                    &format!("{linkmaze_path}/{link}.html")[..],
-                                )]
-                                .into_iter(),
-                            )?;
-                            serializer.write_text(&link[..])?;
-                            serializer.end_elem(QualName::new(
-                                None,
-                                Namespace::from(""),
-                                LocalName::from("a"),
-                            ))?;
+                        if json_ld {
+                            let json = handle
+                                .children
+                                .borrow()
+                                .iter()
+                                .filter_map(|child| match child.data {
+                                    NodeData::Text { ref contents } => {
+                                        Some(contents.borrow().to_string())
+                                    }
+                                    _ => None,
+                                })
+                                .collect::<String>();
+                            let output = scramble_json(&json, &self.2.json_ld_keys, |text| {
+                                scramble(
+                                    text,
+                                    Passage {
+                                        lang: scope.lang.as_deref(),
+                                        keep,
+                                        flow: &mut Flow::default(),
+                                    },
+                                    &mut markov,
+                                    &mut rng,
+                                    self.4,
+                                    &self.2.replace,
+                                )
+                            });
+                            serializer.write_text(output.as_deref().unwrap_or(&json))?;
+                            ops.push_front(SerializeOp::Close(name.clone()));
+                            continue;
                         }
 
-                        ops.reserve(1 + handle.children.borrow().len());
+                        if embed_linkmaze && name.local == *"body" && paragraph_links.is_empty() {
+                            for _ in 0..self.2.linkmaze_links.unwrap_or(1) {
+                                write_linkmaze_link(
+                                    serializer,
+                                    &linkmaze_path,
+                                    self.2.linkmaze_style,
+                                    markov.get_mut(scope.lang.as_deref()),
+                                    &mut rng,
+                                )?;
+                            }
+                        }
+
+                        match self.2.canary {
+                            Some(ref canary)
+                                if name.local == *"body" && canary_paragraph.is_none() =>
+                            {
+                                serializer.write_text(&format!("{canary} "))?;
+                            }
+                            _ => {}
+                        }
+
+                        ops.reserve(3 + handle.children.borrow().len());
                         ops.push_front(SerializeOp::Close(name.clone()));
+                        if name.local == *"p" && scope.changeable(on) {
+                            match paragraph_links.get(paragraphs) {
+                                Some(&n_links) if n_links > 0 => {
+                                    ops.push_front(SerializeOp::Linkmaze(n_links))
+                                }
+                                _ => {}
+                            }
+                            match self.2.canary {
+                                Some(ref canary) if canary_paragraph == Some(paragraphs) => {
+                                    ops.push_front(SerializeOp::Text(format!(" {canary}")))
+                                }
+                                _ => {}
+                            }
+                            paragraphs += 1;
+                        }
 
                         for child in handle.children.borrow().iter().rev() {
                             ops.push_front(SerializeOp::Open(child.clone()));
@@ -608,22 +990,37 @@ impl Serialize for SerializableHandle<'_> {
                     NodeData::Doctype { ref name, .. } => serializer.write_doctype(name)?,
 
                     NodeData::Text { ref contents } => {
-                        let mut output = vec![];
-                        for word in contents.borrow().split(' ') {
-                            if rand::random::<f32>() < percent {
-                                output.push(word.into());
-                            } else {
-                                let Some(next) = markov.next() else {
-                                    continue;
-                                };
-                                output.push(std::sync::Arc::unwrap_or_clone(next));
-                            }
+                        let scope = scopes.last().cloned().unwrap_or_default();
+                        let keep = scope.rate.map_or(percent, |rate| 1.0 - rate);
+                        if !on
+                            || scope.skip
+                            || scope.excluded()
+                            || !self.2.replace.changes_text(keep)
+                        {
+                            serializer.write_text(&contents.borrow())?;
+                            continue;
                         }
 
-                        serializer.write_text(&Tendril::<UTF8>::from(output.join(" ")))?
+                        let output = scramble(
+                            &contents.borrow(),
+                            Passage {
+                                lang: scope.lang.as_deref(),
+                                keep,
+                                flow: &mut flow,
+                            },
+                            &mut markov,
+                            &mut rng,
+                            self.4,
+                            &self.2.replace,
+                        );
+
+                        serializer.write_text(&Tendril::<UTF8>::from(output))?
                     }
 
-                    NodeData::Comment { ref contents } => serializer.write_comment(contents)?,
+                    NodeData::Comment { ref contents } => match marker_comment(contents) {
+                        Some(marker) => on = marker,
+                        None => serializer.write_comment(contents)?,
+                    },
 
                     NodeData::ProcessingInstruction {
                         ref target,
@@ -634,8 +1031,27 @@ impl Serialize for SerializableHandle<'_> {
                 },
 
                 SerializeOp::Close(name) => {
+                    if !is_inline(&name) {
+                        flow = Flow::default();
+                    }
+                    scopes.pop();
                     serializer.end_elem(name)?;
                 }
+
+                SerializeOp::Text(text) => serializer.write_text(&text)?,
+
+                SerializeOp::Linkmaze(n_links) => {
+                    let lang = scopes.last().and_then(|scope: &Scope| scope.lang.clone());
+                    for _ in 0..n_links {
+                        write_linkmaze_link(
+                            serializer,
+                            &linkmaze_path,
+                            self.2.linkmaze_style,
+                            markov.get_mut(lang.as_deref()),
+                            &mut rng,
+                        )?;
+                    }
+                }
             }
         }
 
@@ -643,7 +1059,164 @@ impl Serialize for SerializableHandle<'_> {
     }
 }
 
-pub fn tokenize_html(contents: String) -> Vec<String> {
+/// The number of changeable paragraphs below `node`, walking the tree as the serializer does.
+/// `on` is whether marker comments have left changes on so far, and is updated as they are passed.
+fn count_paragraphs(
+    node: &Handle,
+    scopes: &mut Vec<Scope>,
+    on: &mut bool,
+    skip_elements: &HashSet<String>,
+    rules: Option<&Rules>,
+) -> usize {
+    let mut count = 0;
+    for child in node.children.borrow().iter() {
+        match child.data {
+            NodeData::Element { ref name, .. } => {
+                push_scope(scopes, child, skip_elements, rules);
+                let scope = scopes.last().cloned().unwrap_or_default();
+                if name.local == *"p" && scope.changeable(*on) {
+                    count += 1;
+                }
+                count += count_paragraphs(child, scopes, on, skip_elements, rules);
+                scopes.pop();
+            }
+            NodeData::Comment { ref contents } => *on = marker_comment(contents).unwrap_or(*on),
+            _ => {}
+        }
+    }
+    count
+}
+
+fn html_name(local: &str) -> QualName {
+    QualName::new(None, Namespace::from(""), LocalName::from(local))
+}
+
+/// Write a link to a random page in the linkmaze at `path`, with a few words from `markov` as its
+/// text.
+fn write_linkmaze_link<S: Serializer>(
+    serializer: &mut S,
+    path: &str,
+    style: LinkStyle,
+    markov: &mut MarkovIterator<String>,
+    rng: &mut StdRng,
+) -> io::Result<()> {
+    let slug = crate::rand_link(&mut *rng);
+    let n_words = rng.random_range(1..=4);
+    let words = markov.by_ref().take(n_words).collect::<Vec<_>>();
+    let words = words.iter().map(|word| word.as_str()).collect::<Vec<_>>();
+    let text = join_words(&words);
+    let text = text.trim_matches(|c: char| !c.is_alphanumeric());
+    let text = if text.is_empty() { &slug[..] } else { text };
+
+    let mut attrs = vec![(html_name("href"), format!("{path}/{slug}.html"))];
+    if style == LinkStyle::Hidden {
+        attrs.push((
+            html_name("style"),
+            String::from("position:absolute;left:-10000px"),
+        ));
+        attrs.push((html_name("aria-hidden"), String::from("true")));
+        attrs.push((html_name("tabindex"), String::from("-1")));
+    }
+    let wrapper = match style {
+        LinkStyle::Noscript => Some(html_name("noscript")),
+        LinkStyle::Template => Some(html_name("template")),
+        LinkStyle::Visible | LinkStyle::Hidden => None,
+    };
+
+    serializer.write_text(" ")?;
+    if let Some(ref wrapper) = wrapper {
+        serializer.start_elem(wrapper.clone(), iter::empty())?;
+    }
+    serializer.start_elem(
+        html_name("a"),
+        attrs.iter().map(|(name, value)| (name, &value[..])),
+    )?;
+    serializer.write_text(text)?;
+    serializer.end_elem(html_name("a"))?;
+    if let Some(wrapper) = wrapper {
+        serializer.end_elem(wrapper)?;
+    }
+    Ok(())
+}
+
+/// Whether `href` leads to another page on the same site: a relative URL other than a fragment
+/// within the page.  Absolute URLs are never internal, even if they point at the site itself.
+fn is_internal_link(href: &str) -> bool {
+    let href = href.trim();
+    let scheme = href
+        .find([':', '/', '?', '#'])
+        .is_some_and(|i| href[i..].starts_with(':'));
+    !(href.is_empty() || href.starts_with('#') || href.starts_with("//") || scheme)
+}
+
+/// Whether an element is a `<script>` holding JSON-LD structured data.
+fn is_json_ld(name: &QualName, attrs: &[Attribute]) -> bool {
+    name.local == *"script"
+        && attr(attrs, "type")
+            .is_some_and(|kind| kind.trim().eq_ignore_ascii_case("application/ld+json"))
+}
+
+/// Rebuild a JSON document with the strings under any of `keys`, at any depth, passed through
+/// `scramble`.  Returns `None` if `json` isn't valid JSON.
+fn scramble_json(
+    json: &str,
+    keys: &HashSet<String>,
+    mut scramble: impl FnMut(&str) -> String,
+) -> Option<String> {
+    fn walk(
+        value: &mut Value,
+        keys: &HashSet<String>,
+        selected: bool,
+        scramble: &mut impl FnMut(&str) -> String,
+    ) {
+        match value {
+            Value::String(text) if selected => *text = scramble(text),
+            Value::Array(values) => {
+                for value in values {
+                    walk(value, keys, selected, scramble);
+                }
+            }
+            Value::Object(map) => {
+                for (key, value) in map {
+                    walk(value, keys, keys.contains(key), scramble);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut value = serde_json::from_str::<Value>(json).ok()?;
+    walk(&mut value, keys, false, &mut scramble);
+
+    let output = if json.trim().contains('\n') {
+        serde_json::to_string_pretty(&value)
+    } else {
+        serde_json::to_string(&value)
+    };
+    // A "</" in a string could end the script element early, so escape it as JSON allows.
+    Some(output.ok()?.replace("</", "<\\/"))
+}
+
+/// Whether `name` is one of `patterns`, ignoring case.  A pattern ending in `*` matches any name
+/// that starts with the rest of it, e.g. `og:*` matches every OpenGraph property.
+fn name_matches(patterns: &HashSet<String>, name: &str) -> bool {
+    let name = name.trim().to_ascii_lowercase();
+    patterns.iter().any(|pattern| {
+        let pattern = pattern.to_ascii_lowercase();
+        match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == pattern,
+        }
+    })
+}
+
+/// Whether a `<meta>` element's content is prose, rather than a URL or a single keyword such as
+/// the `summary` of `twitter:card`, which would break if it were replaced.
+fn is_prose(content: &str) -> bool {
+    content.split_whitespace().nth(1).is_some() && !content.contains("://")
+}
+
+fn parse_html(contents: &str) -> Handle {
     let opts = ParseOpts {
         tree_builder: TreeBuilderOpts {
             drop_doctype: true,
@@ -652,6 +1225,375 @@ pub fn tokenize_html(contents: String) -> Vec<String> {
         ..Default::default()
     };
 
-    let dom = parse_document(RcDom::default(), opts).one(contents);
-    TokenizeHandle::tokenize(dom.document)
+    parse_document(RcDom::default(), opts)
+        .one(contents)
+        .document
+}
+
+/// The contents of every text node in an HTML document, in document order, except those inside
+/// `skip_elements`.
+pub fn html_text(contents: &str, skip_elements: &HashSet<String>) -> Vec<HtmlText> {
+    TokenizeHandle::text(parse_html(contents), skip_elements)
+}
+
+pub fn tokenize_html(
+    contents: &str,
+    tokenizer: &dyn Tokenizer,
+    skip_elements: &HashSet<String>,
+) -> Vec<String> {
+    TokenizeHandle::tokenize(parse_html(contents), tokenizer, skip_elements)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::markov::MarkovIterator;
+    use crate::perturb::PerturbRates;
+    use crate::seeded_rng;
+    use crate::tokenize::TokenizerKind;
+    use html5ever::serialize;
+
+    /// Options replacing every word outside skipped text, `alt` attributes, OpenGraph metadata and
+    /// JSON-LD descriptions.
+    fn options() -> HandleOptions {
+        HandleOptions {
+            percent: 0.0,
+            embed_linkmaze: false,
+            linkmaze_path: None,
+            linkmaze_links: None,
+            linkmaze_style: LinkStyle::Visible,
+            rewrite_links: 0.0,
+            canary: None,
+            skip_elements: default_skip_elements(),
+            rules: Rules::default(),
+            scramble_attrs: HashSet::from([String::from("alt")]),
+            scramble_meta: HashSet::from([String::from("og:*")]),
+            json_ld_keys: HashSet::from([String::from("description")]),
+            replace: ReplaceOptions::default(),
+        }
+    }
+
+    /// Transform `html` with the default test options.
+    fn transform(html: &str) -> String {
+        transform_with(html, options())
+    }
+
+    /// Transform `html` with a chain that only ever generates "zzz".
+    fn transform_with(html: &str, options: HandleOptions) -> String {
+        let mut markov = LanguageModels::from(
+            MarkovIterator::new((0..10).map(|_| String::from("zzz"))).unwrap(),
+        );
+        let mut rng = seeded_rng(Some(0));
+        let document = SerializableHandle::new(
+            parse_html(html),
+            &mut markov,
+            &mut rng,
+            &TokenizerKind::Auto,
+            options,
+        );
+        let mut out = vec![];
+        serialize(&mut out, &document, Default::default()).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn skip_elements() {
+        assert_eq!(
+            transform(
+                "<head><script>var x = 1;</script><style>p { color: red }</style></head>\
+                 <body><p>one <code>two three</code></p><pre>four\n  five</pre>\
+                 <textarea>six</textarea></body>"
+            ),
+            "<html><head><script>var x = 1;</script><style>p { color: red }</style></head>\
+             <body><p>zzz <code>two three</code></p><pre>four\n  five</pre>\
+             <textarea>six</textarea></body></html>"
+        );
+    }
+
+    #[test]
+    fn markers() {
+        assert_eq!(
+            transform(
+                "<p>one two</p><p data-quixotic=skip>three <b>four</b></p>\
+                 <!-- quixotic:off --><p>five</p><!-- quixotic:on --><p>six<!-- note --></p>"
+            ),
+            "<html><head></head><body><p>zzz zzz</p><p>three <b>four</b></p>\
+             <p>five</p><p>zzz<!-- note --></p></body></html>"
+        );
+        assert_eq!(
+            transform("<p data-quixotic-rate=0>one <i data-quixotic-rate=1>two</i></p>"),
+            "<html><head></head><body><p>one <i>zzz</i></p></body></html>"
+        );
+
+        // A rate marker holds for its whole subtree, even where a rule matches a descendant.
+        assert_eq!(
+            transform_with(
+                "<article data-quixotic-rate=0><p>one two</p></article><p>three</p>",
+                HandleOptions {
+                    percent: 1.0,
+                    rules: "article p { rate: 0.9; } p { rate: 1; }".parse().unwrap(),
+                    ..options()
+                },
+            ),
+            "<html><head></head><body><article><p>one two</p></article><p>zzz</p></body></html>"
+        );
+
+        let text = html_text(
+            "<p data-quixotic=skip>one</p><!--quixotic:off-->two<!--quixotic:on-->three",
+            &default_skip_elements(),
+        );
+        assert_eq!(
+            text.iter().map(|t| &t.text[..]).collect::<Vec<_>>(),
+            ["three"]
+        );
+    }
+
+    #[test]
+    fn attributes() {
+        assert_eq!(
+            transform(
+                "<head><meta name=description content=\"A page\">\
+                 <meta property=og:title content=\"Some title\">\
+                 <meta property=og:image content=\"https://example.com/a b.png\">\
+                 <meta property=og:type content=article></head>\
+                 <img alt=\"a cat\" title=kept src=cat.png><pre><img alt=code></pre>"
+            ),
+            "<html><head><meta name=\"description\" content=\"A page\">\
+             <meta property=\"og:title\" content=\"zzz zzz\">\
+             <meta property=\"og:image\" content=\"https://example.com/a b.png\">\
+             <meta property=\"og:type\" content=\"article\"></head>\
+             <body><img alt=\"zzz zzz\" title=\"kept\" src=\"cat.png\"><pre><img alt=\"code\"></pre>\
+             </body></html>"
+        );
+
+        let patterns = HashSet::from([String::from("description"), String::from("twitter:*")]);
+        assert!(name_matches(&patterns, "Description"));
+        assert!(name_matches(&patterns, "twitter:card"));
+        assert!(!name_matches(&patterns, "og:description"));
+    }
+
+    #[test]
+    fn json_ld() {
+        assert_eq!(
+            transform(
+                "<script type=application/ld+json>{\"name\": \"A page\", \"description\": \"Some text\", \
+                 \"author\": [{\"description\": \"x\"}]}</script>\
+                 <script>var description = \"x\";</script>\
+                 <script type=application/ld+json>{\"description\": \"broken</script>\
+                 <div data-quixotic=skip><script type=application/ld+json>{\"description\": \"kept\"}\
+                 </script></div>"
+            ),
+            "<html><head><script type=\"application/ld+json\">\
+             {\"name\":\"A page\",\"description\":\"zzz zzz\",\"author\":[{\"description\":\"zzz\"}]}\
+             </script><script>var description = \"x\";</script>\
+             <script type=\"application/ld+json\">{\"description\": \"broken</script></head><body>\
+             <div><script type=\"application/ld+json\">{\"description\": \"kept\"}</script></div>\
+             </body></html>"
+        );
+
+        let keys = HashSet::from([String::from("articleBody")]);
+        assert_eq!(
+            scramble_json("{\n\"articleBody\": \"x\"}", &keys, |_| String::from(
+                "</script>"
+            ))
+            .as_deref(),
+            Some("{\n  \"articleBody\": \"<\\/script>\"\n}")
+        );
+    }
+
+    #[test]
+    fn preserve_format() {
+        let html = "<p>\"Hello,\" she said.\n  Done!</p>";
+        assert_eq!(
+            transform(html),
+            "<html><head></head><body><p>\"zzz\" zzz zzz\n  zzz</p></body></html>"
+        );
+
+        let mut options = options();
+        options.replace.preserve_format = true;
+        assert_eq!(
+            transform_with(html, options),
+            "<html><head></head><body><p>\"Zzz,\" zzz zzz.\n  Zzz!</p></body></html>"
+        );
+    }
+
+    #[test]
+    fn linkmaze() {
+        let html = "<body><h1>Title</h1><p>one</p><div><p>two</p></div><p>three</p></body>";
+        let maze_options = |links, style| HandleOptions {
+            percent: 1.0,
+            embed_linkmaze: true,
+            linkmaze_path: Some(String::from("/maze")),
+            linkmaze_links: links,
+            linkmaze_style: style,
+            ..options()
+        };
+
+        let out = transform_with(html, maze_options(None, LinkStyle::Visible));
+        assert!(out.starts_with("<html><head></head><body> <a href=\"/maze/"));
+        assert!(out.contains("zzz</a><h1>Title</h1><p>one</p>"));
+        assert_eq!(out.matches("<a ").count(), 1);
+
+        // Scattered links go at the end of paragraphs.
+        let out = transform_with(html, maze_options(Some(5), LinkStyle::Hidden));
+        assert!(out.contains("<body><h1>Title</h1><p>one"));
+        assert_eq!(out.matches("<a href=\"/maze/").count(), 5);
+        assert_eq!(out.matches("aria-hidden=\"true\"").count(), 5);
+        assert!(out.matches("zzz</a></p>").count() >= 1);
+
+        let out = transform_with(
+            "<body>No paragraphs</body>",
+            maze_options(Some(2), LinkStyle::Template),
+        );
+        assert_eq!(out.matches("<template><a href=\"/maze/").count(), 2);
+
+        // Paragraphs that are left alone never get links.
+        let out = transform_with(
+            "<body><p data-quixotic=skip>one</p><!-- quixotic:off --><p>two</p><!-- quixotic:on -->\
+             <aside><p>three</p></aside><p>four</p></body>",
+            HandleOptions {
+                rules: "aside p { rate: 0; }".parse().unwrap(),
+                ..maze_options(Some(5), LinkStyle::Visible)
+            },
+        );
+        assert!(
+            out.contains("<p>one</p><p>two</p><aside><p>three</p></aside><p>four <a "),
+            "{out}"
+        );
+        assert_eq!(out.matches("<a href=\"/maze/").count(), 5);
+
+        assert_eq!("noscript".parse(), Ok(LinkStyle::Noscript));
+        assert!("invisible".parse::<LinkStyle>().is_err());
+    }
+
+    #[test]
+    fn rewrite_links() {
+        let html =
+            "<body><p><a href=/about.html>About</a> <a href=https://example.com/>Elsewhere</a> \
+                    <a href=#top>Top</a> <a href=mailto:me@example.com>Mail</a></p>\
+                    <nav data-quixotic=skip><a href=index.html>Home</a></nav></body>";
+        let out = transform_with(
+            html,
+            HandleOptions {
+                percent: 1.0,
+                linkmaze_path: Some(String::from("/maze")),
+                rewrite_links: 1.0,
+                ..options()
+            },
+        );
+        assert_eq!(out.matches("<a href=\"/maze/").count(), 1);
+        assert!(out.contains(".html\" data-quixotic-href=\"/about.html\">About</a>"));
+        assert!(out.contains("<a href=\"index.html\">Home</a>"));
+
+        // Links excluded with a rate of 0 are left alone too.
+        let out = transform_with(
+            "<nav><a href=/about.html>About</a></nav>",
+            HandleOptions {
+                percent: 1.0,
+                linkmaze_path: Some(String::from("/maze")),
+                rewrite_links: 1.0,
+                rules: "nav { rate: 0; }".parse().unwrap(),
+                ..options()
+            },
+        );
+        assert!(out.contains("<a href=\"/about.html\">About</a>"), "{out}");
+
+        // Without a linkmaze to lead into, links are left alone.
+        let out = transform_with(
+            html,
+            HandleOptions {
+                percent: 1.0,
+                rewrite_links: 1.0,
+                ..options()
+            },
+        );
+        assert!(out.contains("<a href=\"/about.html\">About</a>"));
+
+        assert!(is_internal_link("../posts/a.html?page=2"));
+        assert!(is_internal_link("./page:2.html"));
+        assert!(!is_internal_link("//example.com/"));
+        assert!(!is_internal_link("javascript:void(0)"));
+    }
+
+    #[test]
+    fn canary() {
+        let options = |canary: &str| HandleOptions {
+            percent: 1.0,
+            canary: Some(String::from(canary)),
+            ..options()
+        };
+        let out = transform_with("<p>One.</p><p>Two.</p>", options("Canary."));
+        assert!(
+            out.contains("<p>One. Canary.</p><p>Two.</p>")
+                || out.contains("<p>One.</p><p>Two. Canary.</p>")
+        );
+        assert_eq!(
+            transform_with("Text", options("Canary.")),
+            "<html><head></head><body>Canary. Text</body></html>"
+        );
+
+        // Paragraphs that are left alone never get the canary.
+        let out = transform_with(
+            "<p data-quixotic=skip>One.</p><!-- quixotic:off --><p>Two.</p><!-- quixotic:on -->\
+             <nav><p>Three.</p></nav><p>Four.</p>",
+            HandleOptions {
+                rules: "nav p { rate: 0; }".parse().unwrap(),
+                ..options("Canary.")
+            },
+        );
+        assert!(out.contains("<p>Four. Canary.</p>"), "{out}");
+        assert_eq!(
+            transform_with("<p data-quixotic=skip>One.</p>", options("Canary.")),
+            "<html><head></head><body>Canary. <p>One.</p></body></html>"
+        );
+    }
+
+    #[test]
+    fn perturb_excluded() {
+        let html = "<nav>Paris on 2024-03-28 for 45 dollars</nav>\
+                    <p data-quixotic-rate=0>Paris on 2024-03-28</p><p>Met Alice on 2024-03-28</p>";
+        let out = transform_with(
+            html,
+            HandleOptions {
+                percent: 1.0,
+                rules: "nav { rate: 0; }".parse().unwrap(),
+                replace: ReplaceOptions {
+                    perturb: PerturbRates {
+                        numbers: 1.0,
+                        dates: 1.0,
+                        names: 1.0,
+                    },
+                    ..ReplaceOptions::default()
+                },
+                ..options()
+            },
+        );
+        assert!(out.contains("<nav>Paris on 2024-03-28 for 45 dollars</nav>"));
+        assert!(out.contains("<p>Paris on 2024-03-28</p>"));
+        assert!(!out.contains("Alice on 2024-03-28"), "{out}");
+    }
+
+    #[test]
+    fn inline_flow() {
+        // Sentences carry on through inline elements, but not into the next block.
+        let out = transform_with(
+            "<p>He met <b>Alice</b> today. <i>Then</i> he left</p><p>Carol stayed.</p>",
+            HandleOptions {
+                percent: 1.0,
+                replace: ReplaceOptions {
+                    perturb: PerturbRates {
+                        names: 1.0,
+                        ..PerturbRates::default()
+                    },
+                    ..ReplaceOptions::default()
+                },
+                ..options()
+            },
+        );
+        assert!(!out.contains("<b>Alice</b>"), "{out}");
+        assert!(
+            out.contains("<i>Then</i> he left</p><p>Carol stayed.</p>"),
+            "{out}"
+        );
+    }
 }
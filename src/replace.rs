@@ -0,0 +1,491 @@
+// Copyright 2024 Marcus Butler <marcusb@marcusb.org>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Replacing the words of a text with words from a chain, shared by the HTML and plain text
+//! transformers.
+
+use std::sync::Arc;
+
+use rand::{rngs::StdRng, Rng};
+
+use crate::markov::{ends_sentence, LanguageModels, MarkovIterator};
+use crate::perturb::{perturb, PerturbRates};
+use crate::tokenize::{Segment, Tokenizer};
+
+/// How replacement words are chosen and fitted into the text.
+#[derive(Clone, Debug, Default)]
+pub struct ReplaceOptions {
+    /// Keep the punctuation around each replaced word and the case of its first letter, and only
+    /// swap the letters and digits at its core.
+    pub preserve_format: bool,
+    /// Choose replacements whose length and capitalisation are close to the original word's, so
+    /// that the page looks the same at a glance.
+    pub match_shape: bool,
+    /// Choose each replacement to follow on from the words before it in the sentence, and where
+    /// possible to lead into the word after it, rather than taking the next word of the chain's
+    /// walk.
+    pub contextual: bool,
+    /// How often numbers, dates and names are swapped for wrong values of the same shape, instead
+    /// of being replaced like other words.
+    pub perturb: PerturbRates,
+}
+
+impl ReplaceOptions {
+    /// Whether [`scramble`] can change a text at all when keeping the fraction `keep` of its words.
+    pub fn changes_text(&self, keep: f32) -> bool {
+        keep < 1.0 || self.perturb.any()
+    }
+}
+
+/// Where [`scramble`] has got to in a run of text, carried from one call to the next so that text
+/// split up by inline markup is treated as one run.
+#[derive(Clone, Debug)]
+pub struct Flow {
+    /// Whether the next word starts a sentence.
+    sentence_start: bool,
+    /// The last words of the current sentence as written out, as many as the order of the chain,
+    /// for choosing replacements in context.
+    context: Vec<String>,
+}
+
+impl Default for Flow {
+    fn default() -> Self {
+        Self {
+            sentence_start: true,
+            context: vec![],
+        }
+    }
+}
+
+/// What is known about a text passed to [`scramble`].
+pub struct Passage<'a> {
+    /// The language of the text, which chooses the chain its replacements come from.
+    pub lang: Option<&'a str>,
+    /// The fraction of words left as they are, chosen at random.
+    pub keep: f32,
+    /// Where the text follows on from, which is left where it ends.
+    pub flow: &'a mut Flow,
+}
+
+/// Rebuild `text` with its words replaced by words from the chain for its language, except for
+/// the fraction `keep` of them, which are left as they are.  Facts are first perturbed at the
+/// rates in `options`, independently of `keep`.
+pub fn scramble(
+    text: &str,
+    Passage { lang, keep, flow }: Passage,
+    markov: &mut LanguageModels,
+    rng: &mut StdRng,
+    tokenizer: &dyn Tokenizer,
+    options: &ReplaceOptions,
+) -> String {
+    let segments = tokenizer.segments_in(text, lang);
+    let words = segments
+        .iter()
+        .filter_map(|segment| match segment {
+            Segment::Word(word) => Some(*word),
+            Segment::Other(_) => None,
+        })
+        .collect::<Vec<_>>();
+
+    let mut out = String::with_capacity(text.len());
+    let mut n_words = 0;
+    for segment in segments {
+        let word = match segment {
+            Segment::Word(word) => word,
+            Segment::Other(other) => {
+                flow.sentence_start |= ends_sentence(other.trim_end());
+                out.push_str(other);
+                continue;
+            }
+        };
+        n_words += 1;
+        if flow.sentence_start {
+            flow.context.clear();
+        }
+
+        let perturbed = if options.perturb.any() {
+            perturb(
+                word,
+                flow.sentence_start,
+                words.get(n_words).copied(),
+                lang,
+                &options.perturb,
+                rng,
+            )
+        } else {
+            None
+        };
+        let mut token = None;
+        let replacement = perturbed.or_else(|| {
+            if rng.random::<f32>() < keep {
+                return None;
+            }
+            let markov = markov.get_mut(lang);
+            let context = Context {
+                previous: &flow.context,
+                following: words.get(n_words).copied(),
+            };
+            let (replaced, replacement) = replacement(word, markov, &context, options)?;
+            token = Some(replaced);
+            Some(replacement)
+        });
+
+        out.push_str(replacement.as_deref().unwrap_or(word));
+        if options.contextual {
+            flow.context.push(token.unwrap_or_else(|| word.to_string()));
+            let excess = flow
+                .context
+                .len()
+                .saturating_sub(markov.get_mut(lang).order());
+            flow.context.drain(..excess);
+        }
+        flow.sentence_start = ends_sentence(word);
+    }
+    out
+}
+
+/// The most candidates drawn from the chain when looking for a replacement of the right shape.
+const SHAPE_CANDIDATES: usize = 16;
+
+/// The words around one being replaced, used when [`ReplaceOptions::contextual`] is set.
+struct Context<'a> {
+    /// The words before it in the sentence, as written out, with the most recent last.
+    previous: &'a [String],
+    /// The word after it in the original text, if any.
+    following: Option<&'a str>,
+}
+
+/// The replacement for `word` from `markov`, fitted to it as set in `options`, along with the
+/// token from the chain it was made from.
+fn replacement(
+    word: &str,
+    markov: &mut MarkovIterator<String>,
+    context: &Context,
+    options: &ReplaceOptions,
+) -> Option<(String, String)> {
+    let following = context.following.map(String::from);
+    let mut candidate = || {
+        let candidate = if options.contextual {
+            markov.next_in_context(context.previous, following.as_ref(), |token| {
+                same_word(word, token)
+            })
+        } else {
+            markov.next()?
+        };
+        let replacement = if options.preserve_format {
+            fit_word(word, &candidate)?
+        } else {
+            String::clone(&candidate)
+        };
+        Some((Arc::unwrap_or_clone(candidate), replacement))
+    };
+    if !options.match_shape {
+        return candidate();
+    }
+
+    // The original word always has the right shape, so it is never a candidate.
+    let mut best: Option<(usize, (String, String))> = None;
+    for _ in 0..SHAPE_CANDIDATES {
+        let Some(candidate) = candidate() else {
+            continue;
+        };
+        if same_word(word, &candidate.1) {
+            continue;
+        }
+        let distance = shape_distance(word, &candidate.1);
+        match best {
+            Some((best_distance, _)) if best_distance <= distance => {}
+            _ => best = Some((distance, candidate)),
+        }
+        if distance == 0 {
+            break;
+        }
+    }
+    best.map(|(_, candidate)| candidate)
+}
+
+/// How different two words look: the difference in their lengths, plus a penalty if they are
+/// capitalised differently.
+fn shape_distance(a: &str, b: &str) -> usize {
+    let case = |word: &str| {
+        let mut letters = word.chars().filter(|c| c.is_alphabetic());
+        let first = letters.next().map(char::is_uppercase);
+        let upper =
+            first == Some(true) && letters.clone().count() > 0 && letters.all(char::is_uppercase);
+        (first, upper)
+    };
+    let length = a.chars().count().abs_diff(b.chars().count());
+    length + if case(a) == case(b) { 0 } else { 2 }
+}
+
+/// Whether two words have the same core, ignoring case.
+fn same_word(a: &str, b: &str) -> bool {
+    split_word(a).1.to_lowercase() == split_word(b).1.to_lowercase()
+}
+
+/// Split a word into the punctuation before it, its core, and the punctuation after it.  The core
+/// starts and ends with a letter or digit, unless there are none.
+pub fn split_word(word: &str) -> (&str, &str, &str) {
+    let start = word.find(char::is_alphanumeric).unwrap_or(word.len());
+    let end = word.rfind(char::is_alphanumeric).map_or(start, |i| {
+        i + word[i..].chars().next().map_or(0, char::len_utf8)
+    });
+    (&word[..start], &word[start..end], &word[end..])
+}
+
+/// The core of `replacement` in place of the core of `word`, keeping the punctuation around `word`
+/// and the case of its first letter.  `None` if either has no letters or digits.
+pub fn fit_word(word: &str, replacement: &str) -> Option<String> {
+    let (prefix, core, suffix) = split_word(word);
+    let (_, new_core, _) = split_word(replacement);
+    let first = core.chars().next()?;
+    let mut chars = new_core.chars();
+    let new_first = chars.next()?;
+
+    let mut out = String::with_capacity(prefix.len() + new_core.len() + suffix.len());
+    out.push_str(prefix);
+    if first.is_uppercase() {
+        out.extend(new_first.to_uppercase());
+    } else if first.is_lowercase() {
+        out.extend(new_first.to_lowercase());
+    } else {
+        out.push(new_first);
+    }
+    out.push_str(chars.as_str());
+    out.push_str(suffix);
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::markov::Trainer;
+    use crate::seeded_rng;
+    use crate::tokenize::TokenizerKind;
+
+    #[test]
+    fn fit() {
+        assert_eq!(split_word("(Hello!)"), ("(", "Hello", "!)"));
+        assert_eq!(split_word("it's"), ("", "it's", ""));
+        assert_eq!(split_word("--"), ("--", "", ""));
+        assert_eq!(split_word("東京。"), ("", "東京", "。"));
+
+        assert_eq!(fit_word("Hello,", "world.").as_deref(), Some("World,"));
+        assert_eq!(fit_word("\"end.\"", "Paris").as_deref(), Some("\"paris.\""));
+        assert_eq!(fit_word("42;", "(answer)").as_deref(), Some("answer;"));
+        assert_eq!(fit_word("word", "..."), None);
+    }
+
+    #[test]
+    fn perturb_facts() {
+        let mut markov = LanguageModels::from(
+            MarkovIterator::new((0..10).map(|_| String::from("zzz"))).unwrap(),
+        );
+        let mut rng = seeded_rng(Some(0));
+        let text = "Alice paid $12.50 on 2024-03-28. Then she left.";
+        let numbers = ReplaceOptions {
+            perturb: PerturbRates {
+                numbers: 1.0,
+                ..PerturbRates::default()
+            },
+            ..ReplaceOptions::default()
+        };
+        assert!(!ReplaceOptions::default().changes_text(1.0));
+        assert!(numbers.changes_text(1.0));
+
+        let out = scramble(
+            text,
+            Passage {
+                lang: None,
+                keep: 1.0,
+                flow: &mut Flow::default(),
+            },
+            &mut markov,
+            &mut rng,
+            &TokenizerKind::Auto,
+            &numbers,
+        );
+        assert_ne!(out, text);
+        assert!(out.starts_with("Alice paid $") && out.ends_with(" on 2024-03-28. Then she left."));
+
+        let names = ReplaceOptions {
+            perturb: PerturbRates {
+                names: 1.0,
+                ..PerturbRates::default()
+            },
+            ..ReplaceOptions::default()
+        };
+        let out = scramble(
+            "He met Alice in Paris. Then he saw Bob Jones.",
+            Passage {
+                lang: None,
+                keep: 1.0,
+                flow: &mut Flow::default(),
+            },
+            &mut markov,
+            &mut rng,
+            &TokenizerKind::Auto,
+            &names,
+        );
+        let words = out.split(' ').collect::<Vec<_>>();
+        assert_eq!(words[..2], ["He", "met"]);
+        assert_eq!(words[3], "in");
+        assert_eq!(words[5..8], ["Then", "he", "saw"]);
+        assert!(words[2] != "Alice" && words[4] != "Paris." && words[8] != "Bob");
+
+        // German capitalises every noun, and neither contractions of "I" nor "Sept" are names.
+        for (text, lang) in [
+            ("Sie sah die Katze im Garten.", Some("de")),
+            ("He said I'm sure on Sept. 3.", None),
+        ] {
+            let out = scramble(
+                text,
+                Passage {
+                    lang,
+                    keep: 1.0,
+                    flow: &mut Flow::default(),
+                },
+                &mut markov,
+                &mut rng,
+                &TokenizerKind::Auto,
+                &names,
+            );
+            assert_eq!(out, text);
+        }
+    }
+
+    #[test]
+    fn match_shape() {
+        assert_eq!(shape_distance("cat", "dog"), 0);
+        assert_eq!(shape_distance("cat", "Dog"), 2);
+        assert_eq!(shape_distance("NASA", "Nasa"), 2);
+        assert_eq!(shape_distance("Hello,", "hi"), 6);
+
+        let corpus = "a Extraordinarily b tremendously cat perpendicular dog Dog fox";
+        let mut markov = MarkovIterator::new(corpus.split(' ').map(String::from)).unwrap();
+        markov.set_rng(seeded_rng(Some(1)));
+        let options = ReplaceOptions {
+            match_shape: true,
+            ..ReplaceOptions::default()
+        };
+        let context = Context {
+            previous: &[],
+            following: None,
+        };
+        for _ in 0..20 {
+            let (_, word) = replacement("owl", &mut markov, &context, &options).unwrap();
+            assert!(["cat", "dog", "fox"].contains(&&word[..]), "{word}");
+        }
+
+        let corpus = "owl cat Owl dog owl. fox";
+        let mut markov = MarkovIterator::new(corpus.split(' ').map(String::from)).unwrap();
+        markov.set_rng(seeded_rng(Some(1)));
+        for _ in 0..20 {
+            let (_, word) = replacement("owl", &mut markov, &context, &options).unwrap();
+            assert!(["cat", "dog", "fox"].contains(&&word[..]), "{word}");
+        }
+    }
+
+    #[test]
+    fn contextual() {
+        let corpus = "the cat sat on the mat . a dog ran to the park . the cat ate the fish .";
+        let mut markov =
+            LanguageModels::from(MarkovIterator::new(corpus.split(' ').map(String::from)).unwrap());
+        let mut rng = seeded_rng(Some(2));
+        let options = ReplaceOptions {
+            contextual: true,
+            ..ReplaceOptions::default()
+        };
+
+        // With every word replaced, each one follows on from the replacement before it, even across
+        // calls sharing a flow, so the output is a run of word pairs seen in the corpus.
+        let pairs = corpus
+            .split(' ')
+            .collect::<Vec<_>>()
+            .windows(2)
+            .map(|pair| (pair[0], pair[1]))
+            .collect::<Vec<_>>();
+        for _ in 0..10 {
+            let mut flow = Flow::default();
+            let out = ["one two ", "three four five"]
+                .iter()
+                .map(|text| {
+                    scramble(
+                        text,
+                        Passage {
+                            lang: None,
+                            keep: 0.0,
+                            flow: &mut flow,
+                        },
+                        &mut markov,
+                        &mut rng,
+                        &TokenizerKind::Auto,
+                        &options,
+                    )
+                })
+                .collect::<String>();
+            let words = out.split(' ').collect::<Vec<_>>();
+            assert_eq!(words.len(), 5, "{out}");
+            for pair in words.windows(2) {
+                assert!(pairs.contains(&(pair[0], pair[1])), "{out}");
+            }
+            // Only as many words as the chain's order are kept as context.
+            assert_eq!(flow.context.len(), 1);
+        }
+
+        // Kept words are context too, and the replacement leads into the word after it where it can.
+        let mut markov = MarkovIterator::new(corpus.split(' ').map(String::from)).unwrap();
+        markov.set_rng(seeded_rng(Some(3)));
+        let previous = [String::from("the")];
+        for _ in 0..10 {
+            let context = Context {
+                previous: &previous,
+                following: Some("sat"),
+            };
+            let (token, _) = replacement("owl", &mut markov, &context, &options).unwrap();
+            assert_eq!(token, "cat");
+        }
+
+        // Trained on the text itself, the context would lead back to the original words, so they
+        // are avoided.
+        let text = "The cat sat on the mat. The dog sat on the log. A cat ate the fish.";
+        let mut trainer = Trainer::new(1);
+        trainer.add_text(text);
+        let mut markov = LanguageModels::from(trainer.finish().unwrap());
+        let out = scramble(
+            text,
+            Passage {
+                lang: None,
+                keep: 0.0,
+                flow: &mut Flow::default(),
+            },
+            &mut markov,
+            &mut rng,
+            &TokenizerKind::Auto,
+            &options,
+        );
+        let unchanged = out
+            .split(' ')
+            .zip(text.split(' '))
+            .filter(|(a, b)| a == b)
+            .count();
+        assert!(unchanged < text.split(' ').count() / 2, "{out}");
+    }
+}
@@ -0,0 +1,458 @@
+// Copyright 2024 Marcus Butler <marcusb@marcusb.org>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Rules that set the replacement rate for parts of a document, chosen with CSS selectors.
+//!
+//! A rules file is written like a stylesheet, with a `rate` between 0.0 (never replace) and 1.0
+//! (always replace) in place of style properties:
+//!
+//! ```css
+//! /* Scramble articles heavily, headings lightly and navigation not at all. */
+//! article p { rate: 0.5; }
+//! h1, h2, h3 { rate: 0.05; }
+//! nav, footer, #comments { rate: 0; }
+//! ```
+//!
+//! Selectors support element names, `*`, `#id`, `.class`, `[attr]` and `[attr=value]`, combined
+//! with the descendant (space) and child (`>`) combinators.  As in CSS, an element takes its rate
+//! from the most specific rule that matches it, with later rules winning ties, and otherwise
+//! inherits its parent's.
+
+use std::{
+    fmt::{self, Display},
+    fs::read_to_string,
+    io::Error,
+    path::Path,
+    str::FromStr,
+};
+
+use crate::rcdom::{Handle, NodeData};
+
+#[derive(Debug)]
+pub enum RulesError {
+    /// A selector couldn't be parsed.
+    Selector { selector: String, reason: String },
+    /// A rule body was missing its rate, or had a property other than `rate`.
+    Declaration { selector: String, reason: String },
+    /// The rules file could not be read.
+    Io(Error),
+}
+
+impl Display for RulesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RulesError::Selector { selector, reason } => {
+                write!(f, "invalid selector {selector:?}: {reason}")
+            }
+            RulesError::Declaration { selector, reason } => {
+                write!(f, "invalid rule for {selector:?}: {reason}")
+            }
+            RulesError::Io(e) => write!(f, "unable to read the rules: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for RulesError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RulesError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for RulesError {
+    fn from(e: Error) -> Self {
+        RulesError::Io(e)
+    }
+}
+
+/// A condition on a single element.
+#[derive(Clone, Debug, PartialEq)]
+enum Condition {
+    Name(String),
+    Id(String),
+    Class(String),
+    HasAttr(String),
+    AttrEquals(String, String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Combinator {
+    Descendant,
+    Child,
+}
+
+/// A selector made of compound selectors joined by combinators, stored right to left: the first
+/// compound must match the element itself.
+#[derive(Clone, Debug, PartialEq)]
+struct Selector {
+    compounds: Vec<Vec<Condition>>,
+    /// `combinators[i]` joins `compounds[i]` to `compounds[i + 1]`.
+    combinators: Vec<Combinator>,
+}
+
+impl Selector {
+    /// CSS specificity: (ids, classes and attributes, element names).
+    fn specificity(&self) -> (u32, u32, u32) {
+        let mut specificity = (0, 0, 0);
+        for condition in self.compounds.iter().flatten() {
+            match condition {
+                Condition::Id(_) => specificity.0 += 1,
+                Condition::Class(_) | Condition::HasAttr(_) | Condition::AttrEquals(..) => {
+                    specificity.1 += 1
+                }
+                Condition::Name(_) => specificity.2 += 1,
+            }
+        }
+        specificity
+    }
+
+    fn matches(&self, element: &Handle) -> bool {
+        self.matches_from(0, element)
+    }
+
+    fn matches_from(&self, idx: usize, element: &Handle) -> bool {
+        if !matches_compound(&self.compounds[idx], element) {
+            return false;
+        }
+        if idx + 1 == self.compounds.len() {
+            return true;
+        }
+
+        let mut ancestor = parent(element);
+        match self.combinators[idx] {
+            Combinator::Child => ancestor.is_some_and(|parent| self.matches_from(idx + 1, &parent)),
+            Combinator::Descendant => {
+                while let Some(current) = ancestor {
+                    if self.matches_from(idx + 1, &current) {
+                        return true;
+                    }
+                    ancestor = parent(&current);
+                }
+                false
+            }
+        }
+    }
+}
+
+impl FromStr for Selector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut compounds = vec![];
+        let mut combinators = vec![];
+        let mut pending = None;
+
+        for part in s.replace('>', " > ").split_whitespace() {
+            if part == ">" {
+                if compounds.is_empty() || pending.is_some() {
+                    return Err(String::from("misplaced '>'"));
+                }
+                pending = Some(Combinator::Child);
+                continue;
+            }
+
+            if !compounds.is_empty() {
+                combinators.push(pending.take().unwrap_or(Combinator::Descendant));
+            }
+            compounds.push(parse_compound(part)?);
+        }
+
+        if compounds.is_empty() {
+            return Err(String::from("empty selector"));
+        }
+        if pending.is_some() {
+            return Err(String::from("selector ends with '>'"));
+        }
+
+        compounds.reverse();
+        combinators.reverse();
+        Ok(Selector {
+            compounds,
+            combinators,
+        })
+    }
+}
+
+fn parse_compound(s: &str) -> Result<Vec<Condition>, String> {
+    let ident_end = |s: &str| {
+        s.find(|c: char| !(c.is_alphanumeric() || c == '-' || c == '_'))
+            .unwrap_or(s.len())
+    };
+
+    let mut conditions = vec![];
+    let mut rest = s;
+    if let Some(tail) = rest.strip_prefix('*') {
+        rest = tail;
+    } else {
+        let end = ident_end(rest);
+        if end > 0 {
+            conditions.push(Condition::Name(rest[..end].to_ascii_lowercase()));
+            rest = &rest[end..];
+        }
+    }
+
+    while let Some(c) = rest.chars().next() {
+        match c {
+            '#' | '.' => {
+                let end = ident_end(&rest[1..]);
+                if end == 0 {
+                    return Err(format!("expected a name after '{c}'"));
+                }
+                let name = rest[1..1 + end].to_string();
+                conditions.push(match c {
+                    '#' => Condition::Id(name),
+                    _ => Condition::Class(name),
+                });
+                rest = &rest[1 + end..];
+            }
+            '[' => {
+                let Some(close) = rest.find(']') else {
+                    return Err(String::from("unclosed '['"));
+                };
+                let inner = &rest[1..close];
+                conditions.push(match inner.split_once('=') {
+                    Some((attr, value)) => Condition::AttrEquals(
+                        attr.trim().to_ascii_lowercase(),
+                        value.trim().trim_matches(['"', '\'']).to_string(),
+                    ),
+                    None => Condition::HasAttr(inner.trim().to_ascii_lowercase()),
+                });
+                rest = &rest[close + 1..];
+            }
+            _ => return Err(format!("unsupported syntax at {rest:?}")),
+        }
+    }
+
+    Ok(conditions)
+}
+
+fn matches_compound(conditions: &[Condition], element: &Handle) -> bool {
+    let NodeData::Element {
+        ref name,
+        ref attrs,
+        ..
+    } = element.data
+    else {
+        return false;
+    };
+    let attrs = attrs.borrow();
+    let attr = |wanted: &str| {
+        attrs
+            .iter()
+            .find(|attr| &*attr.name.local == wanted)
+            .map(|attr| &attr.value[..])
+    };
+
+    conditions.iter().all(|condition| match condition {
+        Condition::Name(wanted) => &*name.local == wanted,
+        Condition::Id(id) => attr("id") == Some(id.as_str()),
+        Condition::Class(class) => {
+            attr("class").is_some_and(|classes| classes.split_whitespace().any(|c| c == class))
+        }
+        Condition::HasAttr(wanted) => attr(wanted).is_some(),
+        Condition::AttrEquals(wanted, value) => attr(wanted) == Some(value.as_str()),
+    })
+}
+
+fn parent(node: &Handle) -> Option<Handle> {
+    let weak = node.parent.take()?;
+    let parent = weak.upgrade();
+    node.parent.set(Some(weak));
+    parent
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Rule {
+    selector: Selector,
+    rate: f32,
+}
+
+/// A list of rules setting the replacement rate of the elements they match.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+impl Rules {
+    pub fn load(path: impl AsRef<Path>) -> Result<Rules, RulesError> {
+        read_to_string(path)?.parse()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The rate set for `element` by the most specific rule that matches it, if any.
+    pub fn rate(&self, element: &Handle) -> Option<f32> {
+        let mut best: Option<(&Rule, (u32, u32, u32))> = None;
+        for rule in &self.rules {
+            let specificity = rule.selector.specificity();
+            if best.is_some_and(|(_, best)| specificity < best) {
+                continue;
+            }
+            if rule.selector.matches(element) {
+                best = Some((rule, specificity));
+            }
+        }
+        best.map(|(rule, _)| rule.rate)
+    }
+}
+
+impl FromStr for Rules {
+    type Err = RulesError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rules = vec![];
+        let mut rest = strip_comments(s);
+        rest = rest.trim().to_string();
+
+        while !rest.is_empty() {
+            let Some(open) = rest.find('{') else {
+                return Err(RulesError::Declaration {
+                    selector: rest.clone(),
+                    reason: String::from("expected '{'"),
+                });
+            };
+            let selectors = rest[..open].trim().to_string();
+            let Some(close) = rest[open..].find('}').map(|close| open + close) else {
+                return Err(RulesError::Declaration {
+                    selector: selectors,
+                    reason: String::from("expected '}'"),
+                });
+            };
+
+            let rate =
+                parse_rate(&rest[open + 1..close]).map_err(|reason| RulesError::Declaration {
+                    selector: selectors.clone(),
+                    reason,
+                })?;
+            for selector in selectors.split(',') {
+                let selector = selector.trim();
+                rules.push(Rule {
+                    selector: selector.parse().map_err(|reason| RulesError::Selector {
+                        selector: selector.to_string(),
+                        reason,
+                    })?,
+                    rate,
+                });
+            }
+
+            rest = rest[close + 1..].trim().to_string();
+        }
+
+        Ok(Rules { rules })
+    }
+}
+
+fn strip_comments(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("/*") {
+        out.push_str(&rest[..start]);
+        rest = match rest[start + 2..].find("*/") {
+            Some(end) => &rest[start + 2 + end + 2..],
+            None => "",
+        };
+    }
+    out.push_str(rest);
+    out
+}
+
+fn parse_rate(body: &str) -> Result<f32, String> {
+    let mut rate = None;
+    for declaration in body.split(';').map(str::trim).filter(|d| !d.is_empty()) {
+        match declaration.split_once(':') {
+            Some((property, value)) if property.trim() == "rate" => {
+                let value = value
+                    .trim()
+                    .parse::<f32>()
+                    .map_err(|_| format!("rate {:?} is not a number", value.trim()))?;
+                if !(0.0..=1.0).contains(&value) {
+                    return Err(format!("rate {value} is not between 0 and 1"));
+                }
+                rate = Some(value);
+            }
+            _ => return Err(format!("unsupported declaration {declaration:?}")),
+        }
+    }
+    rate.ok_or_else(|| String::from("missing rate"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rcdom::RcDom;
+    use html5ever::{parse_document, tendril::TendrilSink};
+
+    /// Find the first element named `name` in a depth-first walk.
+    fn find(node: &Handle, name: &str) -> Option<Handle> {
+        if let NodeData::Element { name: ref n, .. } = node.data {
+            if n.local == *name {
+                return Some(node.clone());
+            }
+        }
+        node.children.borrow().iter().find_map(|c| find(c, name))
+    }
+
+    #[test]
+    fn rates() {
+        let dom = parse_document(RcDom::default(), Default::default()).one(
+            "<body><nav><a href=/>home</a></nav>\
+             <article class='post main'><p>text <em>here</em></p><h1 id=title>t</h1></article>\
+             <footer><p>foot</p></footer></body>",
+        );
+        let rules: Rules = "
+            /* comments are ignored */
+            article p { rate: 0.9 }
+            nav, footer { rate: 0; }
+            footer > p { rate: 0.1 }
+            article.post > #title { rate: 0.2 }
+            h1 { rate: 0.3 }
+            [href] { rate: 0.4 }"
+            .parse()
+            .unwrap();
+
+        let rate = |name| rules.rate(&find(&dom.document, name).unwrap());
+        assert_eq!(rate("p"), Some(0.9));
+        assert_eq!(rate("em"), None);
+        assert_eq!(rate("nav"), Some(0.0));
+        assert_eq!(rate("a"), Some(0.4));
+        assert_eq!(rate("h1"), Some(0.2));
+        assert_eq!(rate("footer"), Some(0.0));
+        assert_eq!(rate("body"), None);
+    }
+
+    #[test]
+    fn errors() {
+        for (rules, error) in [
+            ("p { rate: 2 }", "between 0 and 1"),
+            ("p { colour: red }", "unsupported declaration"),
+            ("p { }", "missing rate"),
+            ("p > { rate: 1 }", "ends with '>'"),
+            ("p:hover { rate: 1 }", "unsupported syntax"),
+            ("p { rate: 1 ", "expected '}'"),
+        ] {
+            let e = rules.parse::<Rules>().unwrap_err();
+            assert!(e.to_string().contains(error), "{rules}: {e}");
+        }
+    }
+}
@@ -0,0 +1,301 @@
+// Copyright 2024 Marcus Butler <marcusb@marcusb.org>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Splitting text into words, shared by training and replacement so that both agree on what a
+//! word is.
+
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+use unicode_segmentation::UnicodeSegmentation;
+
+/// A piece of text as split by a [`Tokenizer`]: either a word, which the chain learns and which
+/// may be replaced, or the text between words (whitespace, quotes, stray punctuation), which is
+/// always kept as is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Segment<'a> {
+    Word(&'a str),
+    Other(&'a str),
+}
+
+impl<'a> Segment<'a> {
+    pub fn as_str(&self) -> &'a str {
+        match self {
+            Segment::Word(s) | Segment::Other(s) => s,
+        }
+    }
+}
+
+pub trait Tokenizer {
+    /// Split `text` into segments.  Concatenating the segments must give back `text` exactly.
+    fn segments<'a>(&self, text: &'a str) -> Vec<Segment<'a>>;
+
+    /// Split `text`, which is written in `lang` (a BCP 47 language tag) if known.  The default
+    /// ignores the language.
+    fn segments_in<'a>(&self, text: &'a str, lang: Option<&str>) -> Vec<Segment<'a>> {
+        let _ = lang;
+        self.segments(text)
+    }
+
+    /// The words in `text`.
+    fn words<'a>(&self, text: &'a str) -> Vec<&'a str> {
+        self.segments(text)
+            .into_iter()
+            .filter_map(|segment| match segment {
+                Segment::Word(word) => Some(word),
+                Segment::Other(_) => None,
+            })
+            .collect()
+    }
+}
+
+/// Characters split off the start and end of whitespace-delimited words.
+const QUOTES: [char; 12] = ['"', '\'', '“', '”', '‘', '’', '«', '»', '(', ')', '[', ']'];
+
+/// Splits text at whitespace.  Surrounding quotes and brackets are split off each word, but other
+/// punctuation stays attached, so words keep their commas and full stops.  Runs without a letter
+/// or digit in them aren't words.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WhitespaceTokenizer;
+
+impl Tokenizer for WhitespaceTokenizer {
+    fn segments<'a>(&self, text: &'a str) -> Vec<Segment<'a>> {
+        let mut segments = vec![];
+        let mut rest = text;
+        while !rest.is_empty() {
+            let space = rest.len() - rest.trim_start().len();
+            if space > 0 {
+                segments.push(Segment::Other(&rest[..space]));
+                rest = &rest[space..];
+                continue;
+            }
+
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let (run, tail) = rest.split_at(end);
+            rest = tail;
+
+            if !run.chars().any(char::is_alphanumeric) {
+                segments.push(Segment::Other(run));
+                continue;
+            }
+
+            let word = run.trim_start_matches(QUOTES);
+            let leading = &run[..run.len() - word.len()];
+            let word = word.trim_end_matches(QUOTES);
+            let trailing = &run[leading.len() + word.len()..];
+
+            if !leading.is_empty() {
+                segments.push(Segment::Other(leading));
+            }
+            segments.push(Segment::Word(word));
+            if !trailing.is_empty() {
+                segments.push(Segment::Other(trailing));
+            }
+        }
+
+        segments
+    }
+}
+
+/// Splits text at Unicode word boundaries ([UAX #29]), so punctuation is separate from words and
+/// scripts written without spaces are split into smaller pieces.
+///
+/// [UAX #29]: https://www.unicode.org/reports/tr29/
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UnicodeTokenizer;
+
+impl Tokenizer for UnicodeTokenizer {
+    fn segments<'a>(&self, text: &'a str) -> Vec<Segment<'a>> {
+        text.split_word_bounds()
+            .map(|piece| {
+                if piece.chars().any(char::is_alphanumeric) {
+                    Segment::Word(piece)
+                } else {
+                    Segment::Other(piece)
+                }
+            })
+            .collect()
+    }
+}
+
+/// The built in tokenizers, by name, e.g. for choosing one on the command line.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TokenizerKind {
+    /// [`UnicodeTokenizer`] for languages written without spaces between words, identified by
+    /// language tag or by script, and [`WhitespaceTokenizer`] for everything else.
+    #[default]
+    Auto,
+    Whitespace,
+    Unicode,
+}
+
+impl Tokenizer for TokenizerKind {
+    fn segments<'a>(&self, text: &'a str) -> Vec<Segment<'a>> {
+        self.segments_in(text, None)
+    }
+
+    fn segments_in<'a>(&self, text: &'a str, lang: Option<&str>) -> Vec<Segment<'a>> {
+        match self {
+            TokenizerKind::Auto if lang.is_some_and(unspaced_lang) || has_unspaced_script(text) => {
+                UnicodeTokenizer.segments(text)
+            }
+            TokenizerKind::Auto | TokenizerKind::Whitespace => WhitespaceTokenizer.segments(text),
+            TokenizerKind::Unicode => UnicodeTokenizer.segments(text),
+        }
+    }
+}
+
+impl FromStr for TokenizerKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(TokenizerKind::Auto),
+            "whitespace" => Ok(TokenizerKind::Whitespace),
+            "unicode" => Ok(TokenizerKind::Unicode),
+            _ => Err(format!(
+                "unknown tokenizer {s:?} (expected \"auto\", \"whitespace\" or \"unicode\")"
+            )),
+        }
+    }
+}
+
+impl Display for TokenizerKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenizerKind::Auto => write!(f, "auto"),
+            TokenizerKind::Whitespace => write!(f, "whitespace"),
+            TokenizerKind::Unicode => write!(f, "unicode"),
+        }
+    }
+}
+
+/// The primary language subtag of a BCP 47 language tag, lowercased, e.g. `ja` for `ja-JP`.
+pub fn primary_language(lang: &str) -> String {
+    lang.split(['-', '_'])
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+/// Whether `lang` is written without spaces between words.
+fn unspaced_lang(lang: &str) -> bool {
+    matches!(
+        &primary_language(lang)[..],
+        "ja" | "zh" | "yue" | "th" | "lo" | "km" | "my"
+    )
+}
+
+/// Whether `text` contains any characters from scripts written without spaces between words.
+fn has_unspaced_script(text: &str) -> bool {
+    text.chars().any(is_unspaced)
+}
+
+/// Whether `c` belongs to a script written without spaces between words: Han, Hiragana, Katakana,
+/// Thai, Lao, Khmer or Myanmar.
+pub fn is_unspaced(c: char) -> bool {
+    matches!(c,
+        '\u{0e00}'..='\u{0eff}'       // Thai, Lao
+        | '\u{1000}'..='\u{109f}'     // Myanmar
+        | '\u{1780}'..='\u{17ff}'     // Khmer
+        | '\u{3000}'..='\u{30ff}'     // CJK punctuation, Hiragana, Katakana
+        | '\u{3400}'..='\u{4dbf}'     // CJK Extension A
+        | '\u{4e00}'..='\u{9fff}'     // CJK Unified Ideographs
+        | '\u{f900}'..='\u{faff}'     // CJK Compatibility Ideographs
+        | '\u{ff00}'..='\u{ffef}'     // Fullwidth forms
+        | '\u{20000}'..='\u{2ffff}'   // CJK Extensions B onwards
+    )
+}
+
+/// Join generated words into running text, with a space between them except where both sides are
+/// in a script written without spaces.
+pub fn join_words(words: &[&str]) -> String {
+    let mut out = String::new();
+    for word in words {
+        let unspaced = out.chars().next_back().is_some_and(is_unspaced)
+            && word.chars().next().is_some_and(is_unspaced);
+        if !out.is_empty() && !unspaced {
+            out.push(' ');
+        }
+        out.push_str(word);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(tokenizer: &dyn Tokenizer, text: &str) {
+        let joined = tokenizer
+            .segments(text)
+            .iter()
+            .map(Segment::as_str)
+            .collect::<String>();
+        assert_eq!(joined, text);
+    }
+
+    #[test]
+    fn whitespace() {
+        let text = "  \"Hello,\" she said (quietly) -- it's   over.\n";
+        round_trip(&WhitespaceTokenizer, text);
+        assert_eq!(
+            WhitespaceTokenizer.words(text),
+            ["Hello,", "she", "said", "quietly", "it's", "over."]
+        );
+    }
+
+    #[test]
+    fn unicode() {
+        let text = "\"Hello,\" she said. 東京は晴れ。";
+        round_trip(&UnicodeTokenizer, text);
+        let words = UnicodeTokenizer.words(text);
+        assert_eq!(words[..3], ["Hello", "she", "said"]);
+        assert!(words.len() > 4);
+    }
+
+    #[test]
+    fn auto() {
+        let english = "The weather is fine.";
+        let japanese = "東京は晴れです。";
+        assert_eq!(TokenizerKind::Auto.words(english).len(), 4);
+        assert_eq!(TokenizerKind::Auto.words(japanese).len(), 7);
+        assert_eq!(TokenizerKind::Whitespace.words(japanese).len(), 1);
+        assert_eq!(
+            join_words(&TokenizerKind::Auto.words(japanese)),
+            "東京は晴れです"
+        );
+        assert_eq!(join_words(&["Tokyo", "は", "晴れ"]), "Tokyo は晴れ");
+
+        // Romanised Japanese is split at word boundaries, punctuation and all, when tagged.
+        let romaji = "Tokyo wa hare desu.";
+        assert_eq!(
+            TokenizerKind::Auto.words(romaji),
+            ["Tokyo", "wa", "hare", "desu."]
+        );
+        assert_eq!(
+            TokenizerKind::Auto.segments_in(romaji, Some("ja-Latn")),
+            UnicodeTokenizer.segments(romaji)
+        );
+    }
+}